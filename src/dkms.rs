@@ -0,0 +1,202 @@
+use std::{fs, io, path::Path};
+
+use clap::Subcommand;
+
+use crate::utils::{get_kernel_version, run_cmd, CommandOptions};
+
+const NVIDIA_DKMS_MODULE: &str = "nvidia";
+const DKMS_KERNEL_POSTINST_HOOK: &str = "/etc/kernel/postinst.d/dkms";
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum DkmsCommand {
+    /// Show the NVIDIA module's DKMS state for each kernel
+    Status,
+
+    /// Rebuild and install the NVIDIA module through DKMS
+    Rebuild {
+        /// Kernel release to build for, defaults to the running kernel
+        #[arg(long)]
+        kernel: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DkmsEntry {
+    pub(crate) module: String,
+    pub(crate) version: String,
+    pub(crate) kernel: Option<String>,
+    pub(crate) state: String,
+}
+
+pub(crate) fn run_dkms_command(command: DkmsCommand) -> io::Result<()> {
+    match command {
+        DkmsCommand::Status => print_status(),
+        DkmsCommand::Rebuild { kernel } => {
+            let kernel = match kernel {
+                Some(kernel) => kernel,
+                None => get_kernel_version()?,
+            };
+            rebuild(&kernel)
+        }
+    }
+}
+
+fn print_status() -> io::Result<()> {
+    let entries = nvidia_entries()?;
+    if entries.is_empty() {
+        println!("The NVIDIA module is not registered with DKMS.");
+        return Ok(());
+    }
+
+    for entry in &entries {
+        println!(
+            "{}/{} {:<30} {}",
+            entry.module,
+            entry.version,
+            entry.kernel.as_deref().unwrap_or("-"),
+            entry.state
+        );
+    }
+
+    if covers_new_kernels()? {
+        println!("DKMS will rebuild the NVIDIA module for newly installed kernels.");
+    } else {
+        println!("DKMS is not set up to rebuild the NVIDIA module for new kernels.");
+    }
+    Ok(())
+}
+
+pub(crate) fn rebuild(kernel: &str) -> io::Result<()> {
+    let entry = nvidia_entries()?.into_iter().next().ok_or_else(|| {
+        io::Error::other("The NVIDIA module is not registered with DKMS, nothing to rebuild")
+    })?;
+
+    let module = format!("{}/{}", entry.module, entry.version);
+    println!("Rebuilding {module} for kernel {kernel}...");
+    run_cmd(
+        "dkms",
+        ["install", "--force", module.as_str(), "-k", kernel],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+pub(crate) fn nvidia_entries() -> io::Result<Vec<DkmsEntry>> {
+    let output = run_cmd(
+        "dkms",
+        ["status", "-m", NVIDIA_DKMS_MODULE],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    Ok(parse_dkms_status(&output.stdout))
+}
+
+pub(crate) fn installed_for_kernel(kernel: &str) -> io::Result<bool> {
+    Ok(nvidia_entries()?
+        .iter()
+        .any(|entry| entry.kernel.as_deref() == Some(kernel) && entry.state == "installed"))
+}
+
+/// DKMS only rebuilds on kernel upgrades when the module is installed for
+/// the running kernel, asks to be autoinstalled and the kernel postinst hook
+/// is in place.
+pub(crate) fn covers_new_kernels() -> io::Result<bool> {
+    if !installed_for_kernel(&get_kernel_version()?)? {
+        return Ok(false);
+    }
+    if !Path::new(DKMS_KERNEL_POSTINST_HOOK).exists() {
+        return Ok(false);
+    }
+
+    let autoinstall = nvidia_entries()?.iter().any(|entry| {
+        let dkms_conf = format!("/usr/src/{}-{}/dkms.conf", entry.module, entry.version);
+        fs::read_to_string(dkms_conf)
+            .map(|content| {
+                content.lines().any(|line| {
+                    line.trim()
+                        .strip_prefix("AUTOINSTALL=")
+                        .is_some_and(|value| value.trim_matches('"').eq_ignore_ascii_case("yes"))
+                })
+            })
+            .unwrap_or(false)
+    });
+    Ok(autoinstall)
+}
+
+// Newer DKMS prints "nvidia/580.82.07, 6.8.0-1015-gcp, x86_64: installed",
+// older releases "nvidia, 580.82.07, 6.8.0-1015-gcp, x86_64: installed" and
+// modules that were only added have no kernel at all: "nvidia/580.82.07: added".
+fn parse_dkms_status(output: &str) -> Vec<DkmsEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (fields, state) = line.rsplit_once(':')?;
+            let state = state.split_whitespace().next()?.to_string();
+
+            let mut fields = fields.split(',').map(str::trim);
+            let first = fields.next()?;
+            let (module, version) = match first.split_once('/') {
+                Some((module, version)) => (module.to_string(), version.to_string()),
+                None => (first.to_string(), fields.next()?.to_string()),
+            };
+            let kernel = fields.next().map(str::to_string);
+
+            Some(DkmsEntry {
+                module,
+                version,
+                kernel,
+                state,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(module: &str, version: &str, kernel: Option<&str>, state: &str) -> DkmsEntry {
+        DkmsEntry {
+            module: module.to_string(),
+            version: version.to_string(),
+            kernel: kernel.map(str::to_string),
+            state: state.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_dkms_status() {
+        let entries = parse_dkms_status(include_str!("../tests/fixtures/dkms-status.txt"));
+        assert_eq!(
+            entries,
+            [
+                entry("nvidia", "570.86.10", Some("6.8.0-1015-gcp"), "installed"),
+                entry("nvidia", "570.86.10", Some("6.8.0-1017-gcp"), "built"),
+                entry("nvidia", "570.86.10", Some("6.8.0-1018-gcp"), "installed"),
+                entry("nvidia-fs", "2.24.2", None, "added"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_old_dkms_status() {
+        let entries = parse_dkms_status(include_str!("../tests/fixtures/dkms-status-old.txt"));
+        assert_eq!(
+            entries,
+            [
+                entry("nvidia", "550.54.14", Some("5.15.0-1049-aws"), "installed"),
+                entry("nvidia", "550.54.14", Some("5.15.0-1051-aws"), "built"),
+                entry("nvidia", "535.161.07", None, "added"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_output_without_modules() {
+        assert!(parse_dkms_status("").is_empty());
+        assert!(parse_dkms_status("\n").is_empty());
+    }
+}
@@ -0,0 +1,616 @@
+use std::{collections::BTreeMap, fs, io};
+
+use crate::{
+    dkms, driver_guard, fabric_manager, gpus,
+    install_cuda::{
+        parse_kernel_module_type, parse_proc_driver_version, NVIDIA_DRIVER_VERSION_FILE,
+    },
+    utils::{command_exists, get_kernel_version, run_cmd, CommandOptions},
+    xid::{self, XidEvent, XidSeverity},
+};
+
+const PROC_MODULES: &str = "/proc/modules";
+const NVIDIA_MODULES: [&str; 4] = ["nvidia", "nvidia_uvm", "nvidia_modeset", "nvidia_drm"];
+const XID_LOOKBACK: &str = "24 hours ago";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Finding {
+    outcome: Outcome,
+    summary: String,
+    hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(summary: impl Into<String>) -> Self {
+        Self {
+            outcome: Outcome::Ok,
+            summary: summary.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(summary: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            outcome: Outcome::Warn,
+            summary: summary.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(summary: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            outcome: Outcome::Fail,
+            summary: summary.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Runs every health check and prints a report. Fails when any check does,
+/// so it can gate a node from a script.
+pub(crate) fn doctor() -> io::Result<()> {
+    let version_file = fs::read_to_string(NVIDIA_DRIVER_VERSION_FILE).ok();
+    let driver_version = version_file.as_deref().and_then(parse_proc_driver_version);
+
+    let mut findings = vec![check_driver(version_file.as_deref())];
+    findings.extend(check_modules(
+        &fs::read_to_string(PROC_MODULES).unwrap_or_default(),
+    ));
+    findings.push(check_dkms()?);
+    findings.push(check_driver_guard(driver_guard::failure()));
+    findings.push(check_xid_errors(&read_kernel_log()?));
+    match nvidia_smi_query_xml()? {
+        Some(xml) => findings.extend(check_gpu_report(&xml)),
+        None => findings.push(Finding::fail(
+            "nvidia-smi -q -x failed, ECC and PCIe state unknown",
+            "Run `ignite cuda verify-driver` to see why nvidia-smi doesn't work.",
+        )),
+    }
+    findings.push(check_persistence_mode());
+    if fabric_manager::required() {
+        findings.push(check_fabric_manager(driver_version.as_deref())?);
+    }
+
+    for finding in &findings {
+        let label = match finding.outcome {
+            Outcome::Ok => "[ok]  ",
+            Outcome::Warn => "[warn]",
+            Outcome::Fail => "[fail]",
+        };
+        println!("{label} {}", finding.summary);
+        if let Some(hint) = &finding.hint {
+            println!("       {hint}");
+        }
+    }
+
+    let count = |outcome| {
+        findings
+            .iter()
+            .filter(|finding| finding.outcome == outcome)
+            .count()
+    };
+    println!();
+    println!(
+        "{} ok, {} warnings, {} failures",
+        count(Outcome::Ok),
+        count(Outcome::Warn),
+        count(Outcome::Fail)
+    );
+
+    if count(Outcome::Fail) > 0 {
+        return Err(io::Error::other("Some GPU health checks failed"));
+    }
+    Ok(())
+}
+
+/// `version_file` is the content of /proc/driver/nvidia/version, if it exists.
+fn check_driver(version_file: Option<&str>) -> Finding {
+    match version_file.and_then(parse_proc_driver_version) {
+        Some(version) => {
+            let flavor = version_file
+                .and_then(parse_kernel_module_type)
+                .map(|flavor| format!(", {flavor} kernel modules"))
+                .unwrap_or_default();
+            Finding::ok(format!("Driver {version} is loaded{flavor}."))
+        }
+        None => Finding::fail(
+            format!("{NVIDIA_DRIVER_VERSION_FILE} doesn't exist, the driver is not loaded."),
+            "Run `ignite cuda install-driver`, or `modprobe nvidia` to see why it doesn't load.",
+        ),
+    }
+}
+
+// /proc/modules lines start with the module name: "nvidia_uvm 1437696 0 - Live 0x0000000000000000 (POE)"
+fn check_modules(proc_modules: &str) -> Vec<Finding> {
+    let loaded: Vec<&str> = proc_modules
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    let missing: Vec<&str> = NVIDIA_MODULES
+        .into_iter()
+        .filter(|module| !loaded.contains(module))
+        .collect();
+
+    if missing.contains(&"nvidia") {
+        return vec![Finding::fail(
+            "The nvidia kernel module is not loaded.",
+            "Run `modprobe nvidia` and check `dmesg` for the reason it fails.",
+        )];
+    }
+    if missing.contains(&"nvidia_uvm") {
+        return vec![Finding::warn(
+            "nvidia_uvm is not loaded, CUDA programs will fail until it is.",
+            "Run `modprobe nvidia_uvm`, or `nvidia-modprobe -u -c=0` as the driver does.",
+        )];
+    }
+    vec![Finding::ok(format!(
+        "Kernel modules loaded: {}.",
+        NVIDIA_MODULES
+            .into_iter()
+            .filter(|module| !missing.contains(module))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))]
+}
+
+fn check_dkms() -> io::Result<Finding> {
+    if !command_exists("dkms")? || dkms::nvidia_entries()?.is_empty() {
+        return Ok(Finding::ok(
+            "The driver is not managed by DKMS, kernel updates are held instead.",
+        ));
+    }
+
+    let kernel = get_kernel_version()?;
+    if !dkms::installed_for_kernel(&kernel)? {
+        return Ok(Finding::fail(
+            format!("DKMS has not installed the NVIDIA module for the running kernel {kernel}."),
+            format!("Run `ignite cuda dkms rebuild --kernel {kernel}`."),
+        ));
+    }
+    if !dkms::covers_new_kernels()? {
+        return Ok(Finding::warn(
+            format!("DKMS built the module for {kernel} but won't rebuild it for new kernels."),
+            "Check `ignite cuda dkms status`, or keep the kernel packages held.",
+        ));
+    }
+    Ok(Finding::ok(format!(
+        "DKMS has the NVIDIA module installed for {kernel} and rebuilds it for new kernels."
+    )))
+}
+
+/// `failure` is the reason the driver guard recorded for its last failed
+/// repair, if there is one.
+fn check_driver_guard(failure: Option<String>) -> Finding {
+    match failure {
+        Some(reason) => Finding::fail(
+            format!("The driver guard failed to repair the driver: {reason}"),
+            format!(
+                "See {}, fix the driver and run `ignite cuda guard run` to clear {}.",
+                driver_guard::GUARD_LOG_FILENAME,
+                driver_guard::GUARD_FAILURE_MARKER
+            ),
+        ),
+        None => Finding::ok("The driver guard has no failed repair on record."),
+    }
+}
+
+/// The kernel log of the last day, from the journal or `dmesg` without one.
+fn read_kernel_log() -> io::Result<String> {
+    if command_exists("journalctl")? {
+        let output = run_cmd(
+            "journalctl",
+            ["-k", "--since", XID_LOOKBACK, "--no-pager", "-q"],
+            CommandOptions {
+                check: false,
+                silent: true,
+                ..Default::default()
+            },
+        )?;
+        if output.status.success() {
+            return Ok(output.stdout);
+        }
+    }
+
+    let output = run_cmd(
+        "dmesg",
+        [] as [&str; 0],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    Ok(output.stdout)
+}
+
+fn check_xid_errors(kernel_log: &str) -> Finding {
+    let events: Vec<XidEvent> = kernel_log.lines().filter_map(xid::parse_xid_line).collect();
+    if events.is_empty() {
+        return Finding::ok("No Xid errors in the kernel log.");
+    }
+
+    // One entry per GPU and code.
+    let mut counts: BTreeMap<(String, u32), (usize, &XidEvent)> = BTreeMap::new();
+    for event in &events {
+        counts
+            .entry((event.pci_address.clone(), event.code))
+            .or_insert((0, event))
+            .0 += 1;
+    }
+    let summary = counts
+        .values()
+        .map(|(count, event)| {
+            format!(
+                "Xid {} on {} x{count} ({})",
+                event.code,
+                event.pci_address,
+                event.description()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    let worst = events
+        .iter()
+        .map(XidEvent::severity)
+        .max()
+        .unwrap_or(XidSeverity::Application);
+    match worst {
+        XidSeverity::Critical => Finding::fail(
+            format!("Critical Xid errors: {summary}."),
+            "Drain the node and reset the GPU (`nvidia-smi -r` or a reboot), replace it if it recurs.",
+        ),
+        XidSeverity::Warning => Finding::warn(
+            format!("Xid errors: {summary}."),
+            "Reset the GPU at the next maintenance window and watch for repeats.",
+        ),
+        XidSeverity::Application => Finding::warn(
+            format!("Application Xid errors: {summary}."),
+            "These usually point at a bug in the workload rather than the hardware.",
+        ),
+    }
+}
+
+fn nvidia_smi_query_xml() -> io::Result<Option<String>> {
+    let output = run_cmd(
+        "nvidia-smi",
+        ["-q", "-x"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    );
+    Ok(output
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| output.stdout))
+}
+
+/// The ECC and PCIe findings for every GPU in `nvidia-smi -q -x`'s report.
+fn check_gpu_report(xml: &str) -> Vec<Finding> {
+    let gpus = xml_elements(xml, "gpu");
+    let mut findings = check_ecc(&gpus);
+    findings.extend(check_pcie_links(&gpus));
+    findings
+}
+
+/// Uncorrectable ECC errors since the last reset of one `<gpu>` element.
+fn uncorrectable_ecc_errors(gpu: &str) -> u64 {
+    let volatile = xml_element(gpu, "ecc_errors")
+        .and_then(|errors| xml_element(errors, "volatile"))
+        .unwrap_or_default();
+    // Before Ampere the counters are by bit count, <double_bit><total>.
+    if let Some(double_bit) = xml_element(volatile, "double_bit") {
+        return xml_number(double_bit, "total");
+    }
+    // Newer drivers split the SRAM counter by parity and SEC-DED, next to
+    // the total that some of them still report.
+    let sram = if xml_element(volatile, "sram_uncorrectable_parity").is_some()
+        || xml_element(volatile, "sram_uncorrectable_secded").is_some()
+    {
+        xml_number(volatile, "sram_uncorrectable_parity")
+            + xml_number(volatile, "sram_uncorrectable_secded")
+    } else {
+        xml_number(volatile, "sram_uncorrectable")
+    };
+    sram + xml_number(volatile, "dram_uncorrectable")
+}
+
+fn check_ecc(gpus: &[&str]) -> Vec<Finding> {
+    gpus.iter()
+        .map(|gpu| {
+            let bus_id = gpu_bus_id(gpu);
+            let uncorrectable = uncorrectable_ecc_errors(gpu);
+            let retired = xml_element(gpu, "retired_pages")
+                .map(|pages| {
+                    xml_elements(pages, "retired_count")
+                        .into_iter()
+                        .filter_map(|count| count.trim().parse::<u64>().ok())
+                        .sum::<u64>()
+                })
+                .unwrap_or(0);
+            let remapped_rows = xml_element(gpu, "remapped_rows").unwrap_or_default();
+            let remap_pending = xml_element(remapped_rows, "remapped_row_pending")
+                .is_some_and(|pending| pending.trim() == "Yes");
+            let remap_failure = xml_element(remapped_rows, "remapped_row_failure")
+                .is_some_and(|failure| failure.trim() == "Yes");
+            let pages_pending = xml_element(gpu, "retired_pages")
+                .and_then(|pages| xml_element(pages, "pending_retirement"))
+                .is_some_and(|pending| pending.trim() == "Yes");
+
+            if remap_failure {
+                Finding::fail(
+                    format!("GPU {bus_id}: row remapping failed."),
+                    "The GPU memory is exhausting its spare rows, have the GPU replaced.",
+                )
+            } else if uncorrectable > 0 {
+                Finding::fail(
+                    format!("GPU {bus_id}: {uncorrectable} uncorrectable ECC errors since the last reset."),
+                    "Drain the node and reset the GPU, replace it if the errors come back.",
+                )
+            } else if remap_pending || pages_pending {
+                Finding::warn(
+                    format!("GPU {bus_id}: memory pages are waiting to be retired or remapped."),
+                    "Reset the GPU (`nvidia-smi -r` or a reboot) to apply them.",
+                )
+            } else {
+                Finding::ok(format!(
+                    "GPU {bus_id}: no uncorrectable ECC errors, {retired} retired pages."
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Only the width is checked, idle GPUs drop to a lower PCIe generation to
+/// save power.
+fn check_pcie_links(gpus: &[&str]) -> Vec<Finding> {
+    gpus.iter()
+        .map(|gpu| {
+            let bus_id = gpu_bus_id(gpu);
+            let link_info = xml_element(gpu, "pci_gpu_link_info").unwrap_or_default();
+            let generation = xml_element(link_info, "pcie_gen").unwrap_or_default();
+            let widths = xml_element(link_info, "link_widths").unwrap_or_default();
+            let (current_gen, max_gen) = (
+                xml_number(generation, "current_link_gen"),
+                xml_number(generation, "max_link_gen"),
+            );
+            let (current_width, max_width) = (
+                xml_number(widths, "current_link_width"),
+                xml_number(widths, "max_link_width"),
+            );
+
+            let link =
+                format!("Gen{current_gen} (max Gen{max_gen}) x{current_width} (max x{max_width})");
+            if current_width < max_width {
+                Finding::warn(
+                    format!("GPU {bus_id}: PCIe link {link} runs below its width."),
+                    "Reseat the GPU or check the riser, a degraded link slows host transfers.",
+                )
+            } else {
+                Finding::ok(format!("GPU {bus_id}: PCIe link {link}."))
+            }
+        })
+        .collect()
+}
+
+fn check_persistence_mode() -> Finding {
+    match gpus::inventory() {
+        Ok(gpus) => {
+            let off: Vec<String> = gpus
+                .iter()
+                .filter(|gpu| !gpu.persistence_mode)
+                .map(|gpu| gpu.index.to_string())
+                .collect();
+            if off.is_empty() {
+                Finding::ok(format!(
+                    "Persistence mode is on for all {} GPUs.",
+                    gpus.len()
+                ))
+            } else {
+                Finding::warn(
+                    format!("Persistence mode is off for GPU {}.", off.join(", ")),
+                    "Run `ignite cuda persistenced install`.",
+                )
+            }
+        }
+        Err(err) => Finding::fail(
+            format!("Couldn't list the GPUs: {err}"),
+            "Run `ignite cuda verify-driver` to see why nvidia-smi doesn't work.",
+        ),
+    }
+}
+
+fn check_fabric_manager(driver_version: Option<&str>) -> io::Result<Finding> {
+    let driver_version = driver_version.unwrap_or_default();
+    if fabric_manager::verify(driver_version, false)? {
+        return Ok(Finding::ok(format!(
+            "Fabric manager {driver_version} is running."
+        )));
+    }
+    Ok(Finding::fail(
+        "NVSwitch found but the fabric manager is not running at the driver's version.",
+        "Check `systemctl status nvidia-fabricmanager`, `ignite cuda install-driver` installs the matching one.",
+    ))
+}
+
+// <pci><pci_bus_id>00000000:04:00.0</pci_bus_id>...</pci>
+fn gpu_bus_id(gpu: &str) -> String {
+    xml_element(gpu, "pci_bus_id")
+        .map(|bus_id| bus_id.trim().to_string())
+        .unwrap_or_else(|| "?".to_string())
+}
+
+/// Reads counters such as `<dram_uncorrectable>0</dram_uncorrectable>` or
+/// `<max_link_width>16x</max_link_width>`, `N/A` counts as 0.
+fn xml_number(xml: &str, tag: &str) -> u64 {
+    xml_element(xml, tag)
+        .map(|value| {
+            value
+                .trim()
+                .trim_end_matches('x')
+                .parse::<u64>()
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
+}
+
+fn xml_element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    xml_elements(xml, tag).into_iter().next()
+}
+
+/// The contents of every `<tag>` element in `xml`. nvidia-smi's report is
+/// simple enough that nested elements never share a name with their parent.
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}");
+    let close = format!("</{tag}>");
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after_name = &rest[start + open.len()..];
+        // Skip longer tags sharing the prefix, e.g. <gpu_name> when looking for <gpu>.
+        if !after_name.starts_with(['>', ' ']) {
+            rest = after_name;
+            continue;
+        }
+        let Some(content_start) = after_name.find('>') else {
+            break;
+        };
+        let content = &after_name[content_start + 1..];
+        let Some(end) = content.find(&close) else {
+            break;
+        };
+        elements.push(&content[..end]);
+        rest = &content[end + close.len()..];
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPU_REPORT: &str = include_str!("../tests/fixtures/nvidia-smi-q-x.xml");
+
+    fn gpu(bus_id: &str) -> &'static str {
+        xml_elements(GPU_REPORT, "gpu")
+            .into_iter()
+            .find(|gpu| gpu_bus_id(gpu) == bus_id)
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_the_gpus_of_the_report() {
+        let bus_ids: Vec<String> = xml_elements(GPU_REPORT, "gpu")
+            .into_iter()
+            .map(gpu_bus_id)
+            .collect();
+        assert_eq!(
+            bus_ids,
+            ["00000000:04:00.0", "00000000:05:00.0", "00000000:3B:00.0"]
+        );
+    }
+
+    #[test]
+    fn counts_uncorrectable_ecc_errors_once() {
+        // Only aggregate errors, which survive resets.
+        assert_eq!(uncorrectable_ecc_errors(gpu("00000000:04:00.0")), 0);
+        // sram_uncorrectable 3 is parity 1 + SEC-DED 2, plus 1 DRAM error.
+        assert_eq!(uncorrectable_ecc_errors(gpu("00000000:05:00.0")), 4);
+        assert_eq!(uncorrectable_ecc_errors(gpu("00000000:3B:00.0")), 0);
+    }
+
+    #[test]
+    fn checks_ecc_retirement_and_remapping() {
+        let findings = check_ecc(&xml_elements(GPU_REPORT, "gpu"));
+        let outcomes: Vec<Outcome> = findings.iter().map(|finding| finding.outcome).collect();
+        assert_eq!(outcomes, [Outcome::Ok, Outcome::Fail, Outcome::Ok]);
+        assert_eq!(
+            findings[1].summary,
+            "GPU 00000000:05:00.0: 4 uncorrectable ECC errors since the last reset."
+        );
+        assert_eq!(
+            findings[2].summary,
+            "GPU 00000000:3B:00.0: no uncorrectable ECC errors, 3 retired pages."
+        );
+    }
+
+    #[test]
+    fn warns_about_downgraded_pcie_links_only() {
+        let findings = check_pcie_links(&xml_elements(GPU_REPORT, "gpu"));
+        let outcomes: Vec<Outcome> = findings.iter().map(|finding| finding.outcome).collect();
+        // The idle GPU 04 runs at Gen1, which is power saving, not a fault.
+        assert_eq!(outcomes, [Outcome::Ok, Outcome::Warn, Outcome::Ok]);
+        assert_eq!(
+            findings[1].summary,
+            "GPU 00000000:05:00.0: PCIe link Gen5 (max Gen5) x8 (max x16) runs below its width."
+        );
+    }
+
+    #[test]
+    fn reports_every_gpu_check() {
+        assert_eq!(check_gpu_report(GPU_REPORT).len(), 6);
+        assert!(check_gpu_report("").is_empty());
+    }
+
+    #[test]
+    fn checks_loaded_modules() {
+        let findings = check_modules(include_str!("../tests/fixtures/proc-modules.txt"));
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].outcome, Outcome::Ok);
+        assert_eq!(
+            findings[0].summary,
+            "Kernel modules loaded: nvidia, nvidia_uvm, nvidia_modeset, nvidia_drm."
+        );
+
+        let findings = check_modules(include_str!("../tests/fixtures/proc-modules-no-uvm.txt"));
+        assert_eq!(findings[0].outcome, Outcome::Warn);
+
+        let findings = check_modules("ena 159744 0 - Live 0x0000000000000000\n");
+        assert_eq!(findings[0].outcome, Outcome::Fail);
+    }
+
+    #[test]
+    fn reads_the_driver_version_file() {
+        let finding = check_driver(Some(include_str!(
+            "../tests/fixtures/driver-version-open.txt"
+        )));
+        assert_eq!(finding.outcome, Outcome::Ok);
+        assert_eq!(
+            finding.summary,
+            "Driver 570.86.10 is loaded, open kernel modules."
+        );
+
+        let finding = check_driver(Some(include_str!(
+            "../tests/fixtures/driver-version-proprietary.txt"
+        )));
+        assert_eq!(
+            finding.summary,
+            "Driver 550.54.14 is loaded, proprietary kernel modules."
+        );
+
+        assert_eq!(check_driver(None).outcome, Outcome::Fail);
+    }
+
+    #[test]
+    fn fails_on_a_failed_guard_repair() {
+        assert_eq!(check_driver_guard(None).outcome, Outcome::Ok);
+
+        let finding = check_driver_guard(Some(
+            "Reinstalling the driver on kernel 6.8.0-1017-gcp didn't fix it.".to_string(),
+        ));
+        assert_eq!(finding.outcome, Outcome::Fail);
+        assert_eq!(
+            finding.summary,
+            "The driver guard failed to repair the driver: \
+            Reinstalling the driver on kernel 6.8.0-1017-gcp didn't fix it."
+        );
+    }
+}
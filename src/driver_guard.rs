@@ -0,0 +1,247 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    panic,
+    path::Path,
+};
+
+use clap::{Subcommand, ValueEnum};
+
+use crate::{
+    dkms, install_cuda, ledger,
+    utils::{get_kernel_version, run_cmd, systemd_quote, CommandOptions},
+    CloudProvider,
+};
+
+const GUARD_UNIT_NAME: &str = "ignite-driver-guard.service";
+const GUARD_UNIT_PATH: &str = "/etc/systemd/system/ignite-driver-guard.service";
+pub(crate) const GUARD_LOG_FILENAME: &str = "/var/log/ignite/driver-guard.log";
+pub(crate) const GUARD_FAILURE_MARKER: &str = "/var/lib/ignite/driver-guard-failed";
+const LEDGER_LAST_REPAIR_KERNEL: &str = "guard.last_repair_kernel";
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum GuardCommand {
+    /// Install and enable the boot-time driver guard unit
+    Install,
+
+    /// Disable and remove the boot-time driver guard unit
+    Uninstall,
+
+    /// Check the driver and repair it if needed, this is what the unit runs
+    Run,
+}
+
+pub(crate) fn run_guard_command(
+    cloud_provider: CloudProvider,
+    command: GuardCommand,
+) -> io::Result<()> {
+    match command {
+        GuardCommand::Install => install_unit(cloud_provider),
+        GuardCommand::Uninstall => uninstall_unit(),
+        GuardCommand::Run => run_guard(cloud_provider),
+    }
+}
+
+fn install_unit(cloud_provider: CloudProvider) -> io::Result<()> {
+    let exe = env::current_exe()?.to_string_lossy().into_owned();
+    let cloud_provider = cloud_provider
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    let command_line = [
+        exe.as_str(),
+        "--cloud-provider",
+        cloud_provider.as_str(),
+        "cuda",
+        "guard",
+        "run",
+    ]
+    .map(systemd_quote)
+    .join(" ");
+
+    // Network is needed when the driver has to be reinstalled, but this still
+    // runs ahead of the services that use the GPU.
+    let unit = format!(
+        "[Unit]\n\
+        Description=Repair the NVIDIA driver after kernel changes\n\
+        Wants=network-online.target\n\
+        After=network-online.target systemd-modules-load.service\n\
+        Before=nvidia-persistenced.service docker.service containerd.service\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart={command_line}\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n"
+    );
+    fs::write(GUARD_UNIT_PATH, unit)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    run_cmd(
+        "systemctl",
+        ["enable", GUARD_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+
+    println!("Installed {GUARD_UNIT_PATH}, it will check the driver on every boot.");
+    println!("Decisions are logged to {GUARD_LOG_FILENAME} and the journal.");
+    Ok(())
+}
+
+fn uninstall_unit() -> io::Result<()> {
+    if !Path::new(GUARD_UNIT_PATH).exists() {
+        println!("The driver guard is not installed.");
+        return Ok(());
+    }
+
+    run_cmd(
+        "systemctl",
+        ["disable", GUARD_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+    fs::remove_file(GUARD_UNIT_PATH)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    println!("Removed {GUARD_UNIT_PATH}.");
+    Ok(())
+}
+
+/// Makes sure the driver works on the running kernel. A DKMS rebuild is
+/// tried first, then a full reinstall, and each kernel only gets one repair
+/// attempt so a broken install can't loop on every boot.
+fn run_guard(cloud_provider: CloudProvider) -> io::Result<()> {
+    let kernel = get_kernel_version()?;
+    let built_for = ledger::get(install_cuda::LEDGER_DRIVER_KERNEL);
+    let built_for_label = built_for.as_deref().unwrap_or("an unknown kernel");
+
+    if install_cuda::verify_driver(false)? {
+        if built_for.as_deref() == Some(kernel.as_str()) {
+            log_decision(&format!("Driver works on kernel {kernel}, nothing to do."))?;
+        } else {
+            log_decision(&format!(
+                "Driver built for {built_for_label} works on kernel {kernel}, recording it."
+            ))?;
+            ledger::record(install_cuda::LEDGER_DRIVER_KERNEL, &kernel)?;
+        }
+        return clear_failure();
+    }
+
+    log_decision(&format!(
+        "Driver is not working on kernel {kernel}, it was built for {built_for_label}."
+    ))?;
+
+    if ledger::get(LEDGER_LAST_REPAIR_KERNEL).as_deref() == Some(kernel.as_str()) {
+        let reason = format!(
+            "A repair was already attempted on kernel {kernel} and the driver still doesn't \
+            work. Run `ignite cuda install-driver` manually."
+        );
+        log_decision(&reason)?;
+        return mark_failure(&reason);
+    }
+    ledger::record(LEDGER_LAST_REPAIR_KERNEL, &kernel)?;
+
+    if !dkms::nvidia_entries()?.is_empty() {
+        log_decision(&format!("Rebuilding the driver through DKMS for {kernel}."))?;
+        let rebuilt = dkms::rebuild(&kernel).and_then(|_| {
+            run_cmd("modprobe", ["nvidia"], CommandOptions::default())?;
+            install_cuda::verify_driver(false)
+        });
+        match rebuilt {
+            Ok(true) => return repaired(&kernel, "DKMS rebuild"),
+            Ok(false) => log_decision("DKMS rebuild finished but the driver still doesn't work.")?,
+            Err(err) => log_decision(&format!("DKMS rebuild failed: {err}"))?,
+        }
+    }
+
+    let Some((cuda_version, mut installer_options)) = install_cuda::recorded_driver_install()
+    else {
+        let reason = "The ledger doesn't record which driver was installed, can't reinstall it.";
+        log_decision(reason)?;
+        return mark_failure(reason);
+    };
+
+    // The guard runs at boot, a kernel install or reboot from here could loop.
+    installer_options.no_reboot = true;
+    log_decision(&format!(
+        "Reinstalling the driver for CUDA {cuda_version} from {}.",
+        installer_options.driver_source
+    ))?;
+    // The install steps still unwrap in places, a panic has to be recorded
+    // like any other failure so that status and doctor report it.
+    let reinstalled = panic::catch_unwind(|| {
+        install_cuda::install_driver(cloud_provider, cuda_version, &installer_options)
+            .and_then(|_| install_cuda::verify_driver(false))
+    })
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(io::Error::other(format!("panicked: {message}")))
+    });
+    match reinstalled {
+        Ok(true) => repaired(&kernel, "reinstall"),
+        Ok(false) => {
+            let reason = format!("Reinstalling the driver on kernel {kernel} didn't fix it.");
+            log_decision(&reason)?;
+            mark_failure(&reason)
+        }
+        Err(err) => {
+            let reason = format!("Reinstalling the driver on kernel {kernel} failed: {err}");
+            log_decision(&reason)?;
+            mark_failure(&reason)
+        }
+    }
+}
+
+fn repaired(kernel: &str, how: &str) -> io::Result<()> {
+    log_decision(&format!("Driver repaired on kernel {kernel} by {how}."))?;
+    ledger::record(install_cuda::LEDGER_DRIVER_KERNEL, kernel)?;
+    clear_failure()
+}
+
+/// Returns the reason of the last failed repair, if there is one.
+pub(crate) fn failure() -> Option<String> {
+    fs::read_to_string(GUARD_FAILURE_MARKER)
+        .ok()
+        .map(|reason| reason.trim().to_string())
+}
+
+fn mark_failure(reason: &str) -> io::Result<()> {
+    if let Some(parent) = Path::new(GUARD_FAILURE_MARKER).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(GUARD_FAILURE_MARKER, format!("{reason}\n"))?;
+    Err(io::Error::other(reason.to_string()))
+}
+
+fn clear_failure() -> io::Result<()> {
+    if Path::new(GUARD_FAILURE_MARKER).exists() {
+        fs::remove_file(GUARD_FAILURE_MARKER)?;
+    }
+    Ok(())
+}
+
+fn log_decision(message: &str) -> io::Result<()> {
+    println!("{message}");
+
+    if let Some(parent) = Path::new(GUARD_LOG_FILENAME).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let timestamp = run_cmd(
+        "date",
+        ["--iso-8601=seconds"],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?
+    .stdout;
+    let timestamp = timestamp.trim();
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(GUARD_LOG_FILENAME)?;
+    writeln!(log, "[{timestamp}] {message}")
+}
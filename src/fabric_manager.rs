@@ -0,0 +1,182 @@
+use std::io;
+
+use crate::{
+    install_cuda::{add_nvidia_cuda_repo, apt_package_available},
+    pci,
+    utils::{run_cmd, CommandOptions},
+};
+
+const FABRIC_MANAGER_SERVICE: &str = "nvidia-fabricmanager.service";
+const FABRIC_MANAGER_PACKAGE: &str = "nvidia-fabricmanager";
+
+/// NVSwitch systems (A100/H100 SXM, e.g. a3-highgpu or p5) can't run CUDA
+/// until the fabric manager has set up the switches.
+pub(crate) fn required() -> bool {
+    pci::nvswitches()
+        .map(|switches| !switches.is_empty())
+        .unwrap_or(false)
+}
+
+/// Installs and holds the fabric manager built for exactly `driver_version`,
+/// any other version refuses to start.
+pub(crate) fn install(driver_version: &str) -> io::Result<()> {
+    println!("NVSwitch found, installing the fabric manager for driver {driver_version}...");
+
+    let branch = driver_version.split('.').next().unwrap_or_default();
+    // Up to the 570 branch the package name carries the branch, from 580 on
+    // there is a single package with one version per driver release.
+    let candidates = [
+        format!("{FABRIC_MANAGER_PACKAGE}-{branch}"),
+        FABRIC_MANAGER_PACKAGE.to_string(),
+    ];
+    if !candidates
+        .iter()
+        .any(|package| apt_package_available(package).unwrap_or(false))
+    {
+        add_nvidia_cuda_repo()?;
+    }
+
+    let mut found = None;
+    for package in &candidates {
+        if let Some(version) = matching_package_version(package, driver_version)? {
+            found = Some((package, version));
+            break;
+        }
+    }
+    let Some((package, version)) = found else {
+        return Err(io::Error::other(format!(
+            "No {FABRIC_MANAGER_PACKAGE} package matches driver {driver_version}"
+        )));
+    };
+
+    let package_spec = format!("{package}={version}");
+    run_cmd(
+        "apt-get",
+        [
+            "install",
+            "-y",
+            "--allow-change-held-packages",
+            package_spec.as_str(),
+        ],
+        CommandOptions::default(),
+    )?;
+    run_cmd(
+        "apt-mark",
+        ["hold", package.as_str()],
+        CommandOptions::default(),
+    )?;
+    run_cmd(
+        "systemctl",
+        ["enable", "--now", FABRIC_MANAGER_SERVICE],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+/// Purges whatever fabric manager package is installed, since it can't
+/// outlive the driver it was built for.
+pub(crate) fn remove() -> io::Result<()> {
+    let output = run_cmd(
+        "dpkg-query",
+        [
+            "-W",
+            "-f",
+            "${Package} ${Status}\n",
+            &format!("{FABRIC_MANAGER_PACKAGE}*"),
+        ],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    for package in output
+        .stdout
+        .lines()
+        .filter(|line| line.ends_with(" installed"))
+        .filter_map(|line| line.split_whitespace().next())
+    {
+        println!("Removing {package}...");
+        run_cmd("apt-mark", ["unhold", package], CommandOptions::default())?;
+        run_cmd(
+            "apt-get",
+            ["purge", "-y", package],
+            CommandOptions::default(),
+        )?;
+    }
+    Ok(())
+}
+
+/// The fabric manager has to be running and match the loaded driver exactly.
+pub(crate) fn verify(driver_version: &str, verbose: bool) -> io::Result<bool> {
+    let active = run_cmd(
+        "systemctl",
+        ["is-active", FABRIC_MANAGER_SERVICE],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?
+    .stdout
+    .trim()
+    .to_string();
+    let version = installed_version()?;
+
+    let working = active == "active" && version.as_deref() == Some(driver_version);
+    if verbose {
+        println!(
+            "Fabric manager: {}, {active}",
+            version.as_deref().unwrap_or("not installed")
+        );
+        if let Some(version) = version.filter(|version| version != driver_version) {
+            println!(
+                "Warning: fabric manager {version} doesn't match driver {driver_version}, \
+                CUDA will report \"system not yet initialized\"."
+            );
+        }
+    }
+    Ok(working)
+}
+
+// Prints "Fabric Manager version is : 570.86.10"
+fn installed_version() -> io::Result<Option<String>> {
+    let output = run_cmd(
+        "nv-fabricmanager",
+        ["--version"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    );
+    Ok(output
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            output
+                .stdout
+                .lines()
+                .find(|line| line.contains("version"))
+                .and_then(|line| line.rsplit(':').next())
+                .map(|version| version.trim().to_string())
+        }))
+}
+
+/// Finds the apt version (`570.86.10-1`) of `package` built for the driver.
+fn matching_package_version(package: &str, driver_version: &str) -> io::Result<Option<String>> {
+    let output = run_cmd(
+        "apt-cache",
+        ["madison", package],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    let prefix = format!("{driver_version}-");
+    Ok(output.stdout.lines().find_map(|line| {
+        let version = line.split('|').nth(1)?.trim();
+        version.starts_with(&prefix).then(|| version.to_string())
+    }))
+}
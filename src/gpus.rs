@@ -0,0 +1,257 @@
+use std::io;
+
+use crate::utils::{run_cmd, CommandOptions};
+
+const GPU_QUERY_FIELDS: &str =
+    "index,uuid,name,memory.total,driver_version,pci.bus_id,persistence_mode";
+
+#[derive(Debug, Clone)]
+pub(crate) struct GpuInfo {
+    pub(crate) index: u32,
+    pub(crate) uuid: String,
+    pub(crate) name: String,
+    /// None when nvidia-smi reports `[N/A]`, e.g. for MIG instances
+    pub(crate) memory_total_mib: Option<u64>,
+    pub(crate) driver_version: String,
+    /// For example `00000000:04:00.0`
+    pub(crate) pci_bus_id: String,
+    pub(crate) persistence_mode: bool,
+}
+
+impl GpuInfo {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "index": self.index,
+            "uuid": self.uuid,
+            "name": self.name,
+            "memory_total_mib": self.memory_total_mib,
+            "driver_version": self.driver_version,
+            "pci_bus_id": self.pci_bus_id,
+            "persistence_mode": self.persistence_mode,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct GpuInventory {
+    pub(crate) gpus: Vec<GpuInfo>,
+    /// Rows nvidia-smi printed instead of a GPU's fields, e.g. "Unable to
+    /// determine the device handle for GPU0000:05:00.0: Unknown Error"
+    pub(crate) errors: Vec<String>,
+}
+
+pub(crate) fn print_gpus(json: bool, expect: Option<usize>) -> io::Result<()> {
+    let GpuInventory { gpus, errors } = query_inventory()?;
+
+    if json {
+        let gpus: Vec<_> = gpus.iter().map(GpuInfo::to_json).collect();
+        println!("{}", serde_json::Value::Array(gpus));
+    } else {
+        for gpu in &gpus {
+            println!(
+                "GPU {}: {} ({}), {}, bus {}, driver {}, persistence mode {}",
+                gpu.index,
+                gpu.name,
+                gpu.memory_total_mib
+                    .map_or("unknown memory".to_string(), |mib| format!("{mib} MiB")),
+                gpu.uuid,
+                gpu.pci_bus_id,
+                gpu.driver_version,
+                if gpu.persistence_mode { "on" } else { "off" }
+            );
+        }
+    }
+    for error in &errors {
+        eprintln!("nvidia-smi: {error}");
+    }
+
+    match expect {
+        Some(expected) if gpus.len() != expected => Err(io::Error::other(format!(
+            "Expected {expected} GPUs but nvidia-smi reports {} and {} errors, a GPU may have fallen off the bus",
+            gpus.len(),
+            errors.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Every GPU the driver can talk to, as reported by nvidia-smi. GPUs that
+/// nvidia-smi couldn't query are left out.
+pub(crate) fn inventory() -> io::Result<Vec<GpuInfo>> {
+    query_inventory().map(|inventory| inventory.gpus)
+}
+
+/// Like [`inventory`], with the errors nvidia-smi printed for the GPUs it
+/// couldn't query.
+pub(crate) fn query_inventory() -> io::Result<GpuInventory> {
+    let query = format!("--query-gpu={GPU_QUERY_FIELDS}");
+    let output = run_cmd(
+        "nvidia-smi",
+        [query.as_str(), "--format=csv"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    // nvidia-smi exits non-zero when a single GPU fails, the others are
+    // still listed after the header.
+    parse_inventory(&output.stdout).map_err(|err| {
+        if output.status.success() {
+            err
+        } else {
+            io::Error::other(format!(
+                "nvidia-smi failed: {}{}",
+                output.stdout.trim(),
+                output.stderr.trim()
+            ))
+        }
+    })
+}
+
+// The csv starts with a header naming the fields and their units:
+//   index, uuid, name, memory.total [MiB], driver_version, pci.bus_id, persistence_mode
+//   0, GPU-8c5a..., NVIDIA H100 80GB HBM3, 81559 MiB, 570.86.10, 00000000:04:00.0, Enabled
+// Rows for GPUs nvidia-smi can't query are replaced by an error message.
+fn parse_inventory(output: &str) -> io::Result<GpuInventory> {
+    let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return Ok(GpuInventory::default());
+    };
+    let columns: Vec<&str> = header
+        .split(',')
+        .map(|column| column.split('[').next().unwrap_or_default().trim())
+        .collect();
+    let column = |name: &str| {
+        columns
+            .iter()
+            .position(|column| *column == name)
+            .ok_or_else(|| io::Error::other(format!("nvidia-smi output has no {name} column")))
+    };
+    let (index, uuid, name, memory_total, driver_version, pci_bus_id, persistence_mode) = (
+        column("index")?,
+        column("uuid")?,
+        column("name")?,
+        column("memory.total")?,
+        column("driver_version")?,
+        column("pci.bus_id")?,
+        column("persistence_mode")?,
+    );
+    let number = |value: &str| {
+        value
+            .split_whitespace()
+            .next()
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+
+    let mut inventory = GpuInventory::default();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let gpu_index = (fields.len() == columns.len())
+            .then(|| number(fields[index]))
+            .flatten();
+        let Some(gpu_index) = gpu_index else {
+            inventory.errors.push(line.trim().to_string());
+            continue;
+        };
+
+        inventory.gpus.push(GpuInfo {
+            index: gpu_index as u32,
+            uuid: fields[uuid].to_string(),
+            name: fields[name].to_string(),
+            memory_total_mib: number(fields[memory_total]),
+            driver_version: fields[driver_version].to_string(),
+            pci_bus_id: fields[pci_bus_id].to_string(),
+            persistence_mode: fields[persistence_mode] == "Enabled",
+        });
+    }
+    Ok(inventory)
+}
+
+/// The distinct compute capabilities of the local GPUs as CUDA architecture
+/// numbers, e.g. `90` for an H100.
+pub(crate) fn compute_capabilities() -> io::Result<Vec<String>> {
+    let output = run_cmd(
+        "nvidia-smi",
+        ["--query-gpu=compute_cap", "--format=csv,noheader"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "nvidia-smi failed: {}{}",
+            output.stdout.trim(),
+            output.stderr.trim()
+        )));
+    }
+
+    let mut archs: Vec<String> = output
+        .stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|capability| capability.replace('.', ""))
+        .collect();
+    archs.sort_by_key(|arch| arch.parse::<u32>().unwrap_or(0));
+    archs.dedup();
+    Ok(archs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_inventory() {
+        let inventory =
+            parse_inventory(include_str!("../tests/fixtures/nvidia-smi-query-gpu.csv")).unwrap();
+        assert!(inventory.errors.is_empty());
+        assert_eq!(inventory.gpus.len(), 2);
+
+        let gpu = &inventory.gpus[0];
+        assert_eq!(gpu.index, 0);
+        assert_eq!(gpu.uuid, "GPU-8c5a1f3e-2b7d-4c1e-9a0f-1d2e3f4a5b6c");
+        assert_eq!(gpu.name, "NVIDIA H100 80GB HBM3");
+        assert_eq!(gpu.memory_total_mib, Some(81559));
+        assert_eq!(gpu.driver_version, "570.86.10");
+        assert_eq!(gpu.pci_bus_id, "00000000:04:00.0");
+        assert!(gpu.persistence_mode);
+        assert!(!inventory.gpus[1].persistence_mode);
+    }
+
+    #[test]
+    fn keeps_gpus_with_unavailable_fields() {
+        let inventory = parse_inventory(include_str!(
+            "../tests/fixtures/nvidia-smi-query-gpu-na.csv"
+        ))
+        .unwrap();
+        assert!(inventory.errors.is_empty());
+        assert_eq!(inventory.gpus.len(), 2);
+        assert_eq!(inventory.gpus[0].memory_total_mib, None);
+        assert!(!inventory.gpus[0].persistence_mode);
+        assert_eq!(inventory.gpus[1].memory_total_mib, Some(40960));
+    }
+
+    #[test]
+    fn reports_gpus_nvidia_smi_cannot_query() {
+        let inventory = parse_inventory(include_str!(
+            "../tests/fixtures/nvidia-smi-query-gpu-error.csv"
+        ))
+        .unwrap();
+        let indices: Vec<u32> = inventory.gpus.iter().map(|gpu| gpu.index).collect();
+        assert_eq!(indices, [0, 2]);
+        assert_eq!(
+            inventory.errors,
+            ["Unable to determine the device handle for GPU0000:05:00.0: Unknown Error"]
+        );
+    }
+
+    #[test]
+    fn fails_without_a_header() {
+        assert!(parse_inventory("No devices were found\n").is_err());
+        assert!(parse_inventory("").unwrap().gpus.is_empty());
+    }
+}
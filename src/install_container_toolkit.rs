@@ -0,0 +1,229 @@
+use std::{fs, io, path::Path};
+
+use clap::{Args, ValueEnum};
+use tempfile::TempDir;
+
+use crate::utils::{command_exists, run_cmd, CommandOptions};
+
+const CONTAINER_TOOLKIT_GPG_KEY_URL: &str = "https://nvidia.github.io/libnvidia-container/gpgkey";
+const CONTAINER_TOOLKIT_REPO_URL: &str = "https://nvidia.github.io/libnvidia-container/stable/deb";
+const CONTAINER_TOOLKIT_KEYRING: &str = "/usr/share/keyrings/nvidia-container-toolkit-keyring.gpg";
+const CONTAINER_TOOLKIT_SOURCE_FILENAME: &str =
+    "/etc/apt/sources.list.d/nvidia-container-toolkit.list";
+const DOCKER_CONFIG_DIR: &str = "/etc/docker";
+const CONTAINERD_CONFIG_FILENAME: &str = "/etc/containerd/config.toml";
+const CDI_SPEC_FILENAME: &str = "/etc/cdi/nvidia.yaml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ContainerRuntime {
+    Docker,
+    Containerd,
+}
+
+impl std::fmt::Display for ContainerRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerRuntime::Docker => write!(f, "docker"),
+            ContainerRuntime::Containerd => write!(f, "containerd"),
+        }
+    }
+}
+
+impl ContainerRuntime {
+    fn config_path(self) -> &'static str {
+        match self {
+            ContainerRuntime::Docker => DOCKER_CONFIG_DIR,
+            ContainerRuntime::Containerd => CONTAINERD_CONFIG_FILENAME,
+        }
+    }
+
+    fn service(self) -> &'static str {
+        match self {
+            ContainerRuntime::Docker => "docker",
+            ContainerRuntime::Containerd => "containerd",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct InstallContainerToolkitCommand {
+    /// Runtimes to configure, comma-delimited. Defaults to every runtime
+    /// whose config is found on this machine
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub(crate) runtime: Vec<ContainerRuntime>,
+
+    /// Also generate a CDI spec in `/etc/cdi/nvidia.yaml` and enable CDI in
+    /// the runtimes
+    #[arg(long)]
+    pub(crate) cdi: bool,
+}
+
+pub(crate) fn install_container_toolkit(command: InstallContainerToolkitCommand) -> io::Result<()> {
+    // Checked before touching apt, the toolkit is useless without a runtime.
+    let runtimes = if command.runtime.is_empty() {
+        detect_runtimes()
+    } else {
+        command.runtime.clone()
+    };
+    if runtimes.is_empty() {
+        return Err(io::Error::other(format!(
+            "No container runtime found: neither {DOCKER_CONFIG_DIR} nor \
+            {CONTAINERD_CONFIG_FILENAME} exists. Install Docker or containerd first."
+        )));
+    }
+    for runtime in &runtimes {
+        if !Path::new(runtime.config_path()).exists() || !command_exists(runtime.service())? {
+            return Err(io::Error::other(format!(
+                "{runtime} is not installed, expected {} and the `{}` binary",
+                runtime.config_path(),
+                runtime.service()
+            )));
+        }
+    }
+
+    add_container_toolkit_repo()?;
+    run_cmd(
+        "apt-get",
+        ["install", "-y", "nvidia-container-toolkit"],
+        CommandOptions::default(),
+    )?;
+
+    for runtime in &runtimes {
+        configure_runtime(*runtime, command.cdi)?;
+    }
+    if command.cdi {
+        generate_cdi_spec()?;
+    }
+
+    for runtime in &runtimes {
+        verify_runtime(*runtime)?;
+    }
+    println!(
+        "nvidia-container-toolkit is configured for {}.",
+        runtimes
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" and ")
+    );
+    Ok(())
+}
+
+fn detect_runtimes() -> Vec<ContainerRuntime> {
+    [ContainerRuntime::Docker, ContainerRuntime::Containerd]
+        .into_iter()
+        .filter(|runtime| Path::new(runtime.config_path()).exists())
+        .collect()
+}
+
+fn add_container_toolkit_repo() -> io::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key_path = temp_dir.path().join("gpgkey");
+    let key = key_path.to_string_lossy().into_owned();
+    run_cmd(
+        "curl",
+        ["-fsSL", "-o", key.as_str(), CONTAINER_TOOLKIT_GPG_KEY_URL],
+        CommandOptions::default(),
+    )?;
+    run_cmd(
+        "gpg",
+        [
+            "--batch",
+            "--yes",
+            "--dearmor",
+            "-o",
+            CONTAINER_TOOLKIT_KEYRING,
+            key.as_str(),
+        ],
+        CommandOptions::default(),
+    )?;
+
+    let arch = run_cmd(
+        "dpkg",
+        ["--print-architecture"],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?
+    .stdout;
+    fs::write(
+        CONTAINER_TOOLKIT_SOURCE_FILENAME,
+        format!(
+            "deb [signed-by={CONTAINER_TOOLKIT_KEYRING}] {CONTAINER_TOOLKIT_REPO_URL}/{} /\n",
+            arch.trim()
+        ),
+    )?;
+    run_cmd("apt-get", ["update"], CommandOptions::default())?;
+    Ok(())
+}
+
+fn configure_runtime(runtime: ContainerRuntime, cdi: bool) -> io::Result<()> {
+    let runtime_arg = format!("--runtime={runtime}");
+    let mut args = vec!["runtime", "configure", runtime_arg.as_str()];
+    if cdi {
+        args.push("--cdi.enabled");
+    }
+    run_cmd("nvidia-ctk", args, CommandOptions::default())?;
+    run_cmd(
+        "systemctl",
+        ["restart", runtime.service()],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+fn generate_cdi_spec() -> io::Result<()> {
+    let output_arg = format!("--output={CDI_SPEC_FILENAME}");
+    run_cmd(
+        "nvidia-ctk",
+        ["cdi", "generate", output_arg.as_str()],
+        CommandOptions::default(),
+    )?;
+
+    let output = run_cmd(
+        "nvidia-ctk",
+        ["cdi", "list"],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    if !output.stdout.contains("nvidia.com/gpu=") {
+        return Err(io::Error::other(format!(
+            "{CDI_SPEC_FILENAME} was generated but lists no GPU devices"
+        )));
+    }
+    Ok(())
+}
+
+/// Asks the restarted runtime itself whether it picked up the nvidia
+/// runtime, rather than trusting the file `nvidia-ctk` wrote.
+fn verify_runtime(runtime: ContainerRuntime) -> io::Result<()> {
+    let output = match runtime {
+        ContainerRuntime::Docker => run_cmd(
+            "docker",
+            ["info", "--format", "{{json .Runtimes}}"],
+            CommandOptions {
+                silent: true,
+                ..Default::default()
+            },
+        )?,
+        ContainerRuntime::Containerd => run_cmd(
+            "containerd",
+            ["config", "dump"],
+            CommandOptions {
+                silent: true,
+                ..Default::default()
+            },
+        )?,
+    };
+
+    if !output.stdout.contains("nvidia") {
+        return Err(io::Error::other(format!(
+            "{runtime} doesn't list the nvidia runtime after `nvidia-ctk runtime configure`"
+        )));
+    }
+    println!("{runtime} has the nvidia runtime configured.");
+    Ok(())
+}
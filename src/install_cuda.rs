@@ -1,22 +1,41 @@
 use std::{
-    env,
-    fs::{self, File},
-    io::{self, Write},
+    env, fs, io,
     path::{Path, PathBuf},
 };
 
 use clap::{Args, ValueEnum};
 use tempfile::TempDir;
 
-use crate::{utils::*, CloudProvider};
+use crate::{
+    dkms, driver_guard, fabric_manager, gpus, ledger, pci, persistenced, secure_boot, utils::*,
+    CloudProvider,
+};
 
 const PROFILE_FILENAME: &str = "/etc/profile.d/spyral_cuda_install.sh";
-const NCCL_PROFILE_FILENAME: &str = "/etc/profile.d/spyral_nccl.sh";
-const DEFAULT_NCCL_INSTALL_DIR: &str = "/opt/nccl";
-const NCCL_VERSION: &str = "2.30.3-1";
-const NCCL_SOURCE_URL: &str = "https://github.com/NVIDIA/nccl/archive/refs/tags/v2.30.3-1.tar.gz";
-const NVIDIA_PERSISTANCED_INSTALLER: &str =
-    "/usr/share/doc/NVIDIA_GLX-1.0/samples/nvidia-persistenced-init.tar.bz2";
+const LD_CONF_FILENAME: &str = "/etc/ld.so.conf.d/spyral_cuda.conf";
+const CUDA_TOOLKITS_DIRS: [&str; 2] = ["/usr/local", "/opt"];
+const CUDA_SYMLINK: &str = "/usr/local/cuda";
+const DEFAULT_CUDA_REDIST_URL: &str = "https://developer.download.nvidia.com/compute/cuda/redist";
+pub(crate) const CUDA_REDIST_PLATFORM: &str = "linux-x86_64";
+pub(crate) const NVIDIA_DRIVER_VERSION_FILE: &str = "/proc/driver/nvidia/version";
+const NVIDIA_UNINSTALLER: &str = "/usr/bin/nvidia-uninstall";
+pub(crate) const LEDGER_KERNEL_MODULE_TYPE: &str = "driver.kernel_module_type";
+pub(crate) const LEDGER_DRIVER_SOURCE: &str = "driver.source";
+/// Space separated apt packages making up the driver, the driver package first
+pub(crate) const LEDGER_DRIVER_PACKAGE: &str = "driver.package";
+pub(crate) const LEDGER_DRIVER_KERNEL: &str = "driver.kernel";
+pub(crate) const LEDGER_DRIVER_CUDA_VERSION: &str = "driver.cuda_version";
+const LEDGER_DRIVER_BRANCH: &str = "driver.branch";
+const LEDGER_DRIVER_DKMS: &str = "driver.dkms";
+const LEDGER_DRIVER_SIGN_MODULES: &str = "driver.sign_modules";
+const LEDGER_DRIVER_NO_DRM: &str = "driver.no_drm";
+const LEDGER_DRIVER_NO_OPENGL_LIBS: &str = "driver.no_opengl_libs";
+const LEDGER_DRIVER_APT_REPO: &str = "driver.apt_repo";
+const LEDGER_DRIVER_APT_REPO_KEY: &str = "driver.apt_repo_key";
+const CUSTOM_APT_SOURCE_FILENAME: &str = "/etc/apt/sources.list.d/spyral-nvidia-custom.list";
+const CUSTOM_APT_KEYRING: &str = "/usr/share/keyrings/spyral-nvidia-custom.gpg";
+const NOUVEAU_MODULE_DIR: &str = "/sys/module/nouveau";
+const NOUVEAU_BLACKLIST_FILENAME: &str = "/etc/modprobe.d/spyral-blacklist-nouveau.conf";
 
 struct RebootRequired;
 
@@ -39,15 +58,116 @@ impl std::fmt::Display for CudaVersion {
     }
 }
 
+impl CudaVersion {
+    /// Minimum driver for each toolkit, following the "CUDA Toolkit and
+    /// Corresponding Driver Versions" table in NVIDIA's release notes.
+    pub fn minimum_driver_version(&self) -> &'static str {
+        match self {
+            CudaVersion::V12_5 => "555.42.02",
+            CudaVersion::V12_6 => "560.28.03",
+            CudaVersion::V12_8 => "570.26",
+            CudaVersion::V13_0_1 => "580.82.07",
+        }
+    }
+
+    /// Minimum driver for the toolkit's major version, from the "CUDA Minor
+    /// Version Compatibility" table. Drivers between this and
+    /// `minimum_driver_version` run the toolkit's binaries but can't JIT its
+    /// newer PTX.
+    pub fn minimum_compatible_driver_version(&self) -> &'static str {
+        match self {
+            CudaVersion::V12_5 | CudaVersion::V12_6 | CudaVersion::V12_8 => "525.60.13",
+            CudaVersion::V13_0_1 => "580",
+        }
+    }
+
+    /// The full release number used by NVIDIA's redistributable manifests.
+    fn release(&self) -> &'static str {
+        match self {
+            CudaVersion::V12_5 => "12.5.0",
+            CudaVersion::V12_6 => "12.6.0",
+            CudaVersion::V12_8 => "12.8.0",
+            CudaVersion::V13_0_1 => "13.0.1",
+        }
+    }
+
+    /// The `major-minor` suffix NVIDIA uses in package names, e.g. `12-8`.
+    fn package_suffix(&self) -> &'static str {
+        match self {
+            CudaVersion::V12_5 => "12-5",
+            CudaVersion::V12_6 => "12-6",
+            CudaVersion::V12_8 => "12-8",
+            CudaVersion::V13_0_1 => "13-0",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Args)]
-pub(crate) struct InstallNcclCommand {
-    /// Installation directory for NCCL
-    #[arg(long, default_value = DEFAULT_NCCL_INSTALL_DIR)]
-    pub(crate) install_dir: String,
+pub(crate) struct InstallCudaCommand {
+    /// CUDA version to install
+    #[arg(short, long, value_enum, default_value = "v13-0-1")]
+    pub(crate) version: CudaVersion,
+
+    /// Replace an installed driver that is too old for this toolkit
+    #[arg(long, conflicts_with = "allow_forward_compat")]
+    pub(crate) upgrade_driver: bool,
+
+    /// Keep an older driver and install the cuda-compat forward compatibility package
+    #[arg(long)]
+    pub(crate) allow_forward_compat: bool,
+
+    /// Require the driver NVIDIA lists for this exact toolkit release instead
+    /// of accepting any driver of the same major version through CUDA minor
+    /// version compatibility
+    #[arg(long)]
+    pub(crate) strict_driver_check: bool,
 
-    /// Write /etc/profile.d/spyral_nccl.sh for system-wide NCCL environment variables
+    /// Only install the toolkit, never touch the driver or kernel. This is
+    /// implied on machines without an NVIDIA GPU.
     #[arg(long)]
-    pub(crate) write_profile: bool,
+    pub(crate) toolkit_only: bool,
+
+    /// Install only these components from NVIDIA's redistributable archives
+    /// instead of running the full installer, e.g. `nvcc,cudart,cublas`
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) components: Vec<String>,
+
+    /// Base URL of the redistributable archives and their manifests
+    #[arg(long, default_value = DEFAULT_CUDA_REDIST_URL)]
+    pub(crate) redist_url: String,
+
+    /// Install the toolkit here instead of `/usr/local/cuda-X.Y`
+    #[arg(long)]
+    pub(crate) prefix: Option<String>,
+
+    #[command(flatten)]
+    pub(crate) installer: InstallerOptions,
+}
+
+impl InstallCudaCommand {
+    pub(crate) fn from_version(version: CudaVersion) -> Self {
+        Self {
+            version,
+            upgrade_driver: false,
+            allow_forward_compat: false,
+            strict_driver_check: false,
+            toolkit_only: false,
+            components: Vec::new(),
+            redist_url: DEFAULT_CUDA_REDIST_URL.to_string(),
+            prefix: None,
+            installer: InstallerOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct UpgradeDriverCommand {
+    /// Driver to move to, as a branch (`580`) or a full version (`580.82.07`)
+    #[arg(long)]
+    pub(crate) to: String,
+
+    #[command(flatten)]
+    pub(crate) installer: InstallerOptions,
 }
 
 struct CudaConfig {
@@ -91,7 +211,7 @@ impl CudaConfig {
                 toolkit_checksum: String::from("c71027cf1a4ce84f80b9cbf81116e767"),
                 bin_folder: String::from("/usr/local/cuda-12.8/bin"),
                 lib_folder: String::from("/usr/local/cuda-12.8/lib64"),
-                driver_version: String::from("550.54.14"),
+                driver_version: String::from("570.86.10"),
             },
             CudaVersion::V13_0_1 => Self {
                 version,
@@ -99,42 +219,282 @@ impl CudaConfig {
                     "https://developer.download.nvidia.com/compute/cuda/13.0.1/local_installers/cuda_13.0.1_580.82.07_linux.run",
                 ),
                 toolkit_checksum: String::from("8c56e3cb1ab74370aafed5a4600bc5bc"),
-                bin_folder: String::from("/usr/local/cuda-13.0.1/bin"),
-                lib_folder: String::from("/usr/local/cuda-13.0.1/lib64"),
+                bin_folder: String::from("/usr/local/cuda-13.0/bin"),
+                lib_folder: String::from("/usr/local/cuda-13.0/lib64"),
                 driver_version: String::from("580.82.07"),
             },
         }
     }
+
+    /// Moves the toolkit from its default `/usr/local/cuda-X.Y` location.
+    pub fn with_prefix(mut self, prefix: Option<&str>) -> Self {
+        if let Some(prefix) = prefix {
+            let prefix = prefix.trim_end_matches('/');
+            self.bin_folder = format!("{prefix}/bin");
+            self.lib_folder = format!("{prefix}/lib64");
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum KernelModuleType {
+    Open,
+    Proprietary,
+    /// Pick based on the GPUs found on the PCI bus
+    #[default]
+    Auto,
+}
+
+impl std::fmt::Display for KernelModuleType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KernelModuleType::Open => write!(f, "open"),
+            KernelModuleType::Proprietary => write!(f, "proprietary"),
+            KernelModuleType::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl KernelModuleType {
+    /// Device IDs from 0x2300 cover Hopper (GH100) as well as Ada (AD10x,
+    /// e.g. L4 and L40S) and everything newer. These prefer the open modules,
+    /// Blackwell requires them. Older ones such as V100, T4 and A100 get the
+    /// proprietary modules.
+    fn resolve(self) -> io::Result<Self> {
+        if self != KernelModuleType::Auto {
+            return Ok(self);
+        }
+
+        let gpus = pci::nvidia_gpus()?;
+        let all_prefer_open = !gpus.is_empty() && gpus.iter().all(|gpu| gpu.device >= 0x2300);
+        let resolved = if all_prefer_open {
+            KernelModuleType::Open
+        } else {
+            KernelModuleType::Proprietary
+        };
+
+        let device_ids = gpus
+            .iter()
+            .map(|gpu| format!("{:#06x}", gpu.device))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("Using {resolved} kernel modules for GPU device IDs [{device_ids}].");
+        Ok(resolved)
+    }
+
+    /// Both the CUDA and the standalone driver runfile take `-m`.
+    fn installer_arg(self) -> Option<&'static str> {
+        match self {
+            KernelModuleType::Open => Some("-m=kernel-open"),
+            KernelModuleType::Proprietary => Some("-m=kernel"),
+            KernelModuleType::Auto => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DriverSource {
+    /// The driver bundled in the CUDA runfile
+    #[default]
+    Runfile,
+    /// A pinned driver branch from NVIDIA's apt repository, built with DKMS
+    Apt,
+    /// Ubuntu's prebuilt and signed modules for the running cloud kernel,
+    /// falling back to the runfile when there are none
+    UbuntuSigned,
+}
+
+impl std::fmt::Display for DriverSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriverSource::Runfile => write!(f, "runfile"),
+            DriverSource::Apt => write!(f, "apt"),
+            DriverSource::UbuntuSigned => write!(f, "ubuntu-signed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub(crate) struct InstallerOptions {
+    /// Don't install the driver's OpenGL libraries, for headless servers
+    #[arg(long)]
+    pub(crate) no_opengl_libs: bool,
+
+    /// Don't install the nvidia-drm kernel module, for headless servers
+    #[arg(long)]
+    pub(crate) no_drm: bool,
+
+    /// Extra argument passed as-is to the NVIDIA runfile installer, can be repeated
+    #[arg(long = "installer-arg", allow_hyphen_values = true)]
+    pub(crate) installer_args: Vec<String>,
+
+    /// Sign the driver's kernel modules with a MOK key and enroll it. This
+    /// is implied when Secure Boot is enabled.
+    #[arg(long)]
+    pub(crate) sign_modules: bool,
+
+    /// Which flavor of the NVIDIA kernel modules to install
+    #[arg(long, value_enum, default_value_t = KernelModuleType::Auto)]
+    pub(crate) kernel_module_type: KernelModuleType,
+
+    /// Where to install the driver from
+    #[arg(long, value_enum, default_value_t = DriverSource::Runfile)]
+    pub(crate) driver_source: DriverSource,
+
+    /// Driver branch for the apt based driver sources, e.g. `580`. Defaults
+    /// to the branch of the driver bundled with the CUDA version
+    #[arg(long)]
+    pub(crate) driver_branch: Option<String>,
+
+    /// Use this apt repository instead of NVIDIA's with `--driver-source apt`,
+    /// e.g. `file:/srv/nvidia-repo` or an https mirror
+    #[arg(long)]
+    pub(crate) apt_repo: Option<String>,
+
+    /// Signing key of `--apt-repo`, a file or URL. Required unless the
+    /// repository is a local `file:` one
+    #[arg(long)]
+    pub(crate) apt_repo_key: Option<String>,
+
+    /// Register the runfile driver's kernel modules with DKMS
+    #[arg(long)]
+    pub(crate) dkms: bool,
+
+    /// Leave kernel updates unheld when DKMS is confirmed to rebuild the
+    /// driver for new kernels
+    #[arg(long)]
+    pub(crate) unhold_kernel: bool,
+
+    /// Build against the running kernel and fail instead of installing a
+    /// newer kernel or rebooting, for unattended repairs
+    #[arg(long)]
+    pub(crate) no_reboot: bool,
+}
+
+impl InstallerOptions {
+    fn driver_args(&self, kernel_module_type: KernelModuleType) -> Vec<String> {
+        let mut args = Vec::new();
+        args.extend(kernel_module_type.installer_arg().map(String::from));
+        if self.dkms {
+            args.push(String::from("--dkms"));
+        }
+        if self.no_opengl_libs {
+            args.push(String::from("--no-opengl-libs"));
+        }
+        if self.no_drm {
+            args.push(String::from("--no-drm"));
+        }
+        args.extend(self.installer_args.iter().cloned());
+        args
+    }
+
+    /// The standalone driver runfile spells some options differently from
+    /// the CUDA runfile that wraps it.
+    fn standalone_driver_args(&self, kernel_module_type: KernelModuleType) -> Vec<String> {
+        let mut args = Vec::new();
+        args.extend(kernel_module_type.installer_arg().map(String::from));
+        if self.dkms {
+            args.push(String::from("--dkms"));
+        }
+        if self.no_opengl_libs {
+            args.push(String::from("--no-opengl-files"));
+        }
+        if self.no_drm {
+            args.push(String::from("--no-drm"));
+        }
+        args.extend(self.installer_args.iter().cloned());
+        args
+    }
 }
 
 pub(crate) fn install_driver(
     cloud_provider: CloudProvider,
     cuda_version: CudaVersion,
+    installer_options: &InstallerOptions,
 ) -> io::Result<()> {
     let cuda_config = CudaConfig::new(cuda_version);
+    // Refuse an unsigned network repository before touching the system.
+    if let Some(repo) = &installer_options.apt_repo {
+        let keyring = installer_options
+            .apt_repo_key
+            .as_ref()
+            .map(|_| CUSTOM_APT_KEYRING);
+        custom_apt_source(repo, keyring)?;
+    }
 
-    match install_dependencies_debian(cloud_provider) {
-        Ok(_) => {
-            println!("Dependencies installed successfully without requiring a reboot.");
+    if installer_options.no_reboot {
+        install_build_dependencies_debian()?;
+        if disable_nouveau().is_err() {
+            return Err(io::Error::other(
+                "nouveau is still loaded and only a reboot can unload it, \
+                which --no-reboot forbids",
+            ));
         }
-        Err(RebootRequired) => {
-            println!("System will reboot to apply kernel changes.");
-            reboot();
+    } else {
+        // Both steps run before rebooting, so that a single reboot covers them.
+        let dependencies = install_dependencies_debian(cloud_provider);
+        let nouveau = disable_nouveau();
+        match (dependencies, nouveau) {
+            (Ok(_), Ok(_)) => {
+                println!("Dependencies installed successfully without requiring a reboot.");
+            }
+            _ => {
+                println!("System will reboot to apply kernel changes.");
+                reboot();
+            }
         }
     }
 
     println!("Installing GPU drivers for CUDA {}...", cuda_version);
 
-    let installer_path = download_cuda_toolkit_installer(&cuda_config)?;
-    let installer = installer_path.to_string_lossy().into_owned();
-    run_cmd(
-        "sh",
-        [installer.as_str(), "--silent", "--driver"],
-        CommandOptions::default(),
-    )?;
+    let kernel_module_type = installer_options.kernel_module_type.resolve()?;
+    let mut driver_source = installer_options.driver_source;
+    if driver_source == DriverSource::UbuntuSigned
+        && !install_driver_ubuntu_signed(
+            cloud_provider,
+            &cuda_config,
+            installer_options,
+            kernel_module_type,
+        )?
+    {
+        println!("Falling back to the runfile driver install.");
+        driver_source = DriverSource::Runfile;
+    }
+
+    let loadable = match driver_source {
+        DriverSource::Runfile => {
+            install_driver_runfile(&cuda_config, installer_options, kernel_module_type)?
+        }
+        DriverSource::Apt => {
+            install_driver_apt(&cuda_config, installer_options, kernel_module_type)?;
+            true
+        }
+        DriverSource::UbuntuSigned => true,
+    };
+    ledger::record(LEDGER_DRIVER_SOURCE, &driver_source.to_string())?;
+    ledger::record(LEDGER_KERNEL_MODULE_TYPE, &kernel_module_type.to_string())?;
+    ledger::record(LEDGER_DRIVER_KERNEL, &get_kernel_version()?)?;
+    if let Some(value) = cuda_version.to_possible_value() {
+        ledger::record(LEDGER_DRIVER_CUDA_VERSION, value.get_name())?;
+    }
+    record_installer_options(installer_options)?;
+
+    if !loadable {
+        protect_driver_from_kernel_updates(driver_source, installer_options)?;
+        println!("GPU driver installed, it will load once the signing key is enrolled.");
+        return Ok(());
+    }
+
+    if fabric_manager::required() {
+        match installed_driver_version()? {
+            Some(driver_version) => fabric_manager::install(&driver_version)?,
+            None => println!("Driver version unknown, can't install the matching fabric manager."),
+        }
+    }
 
     if verify_driver(true)? {
-        lock_kernel_updates_debian()?;
+        protect_driver_from_kernel_updates(driver_source, installer_options)?;
         println!("GPU driver installed successfully!");
     } else {
         println!("Something went wrong with driver installation, installation failed");
@@ -143,6 +503,303 @@ pub(crate) fn install_driver(
     Ok(())
 }
 
+/// Kernel updates are held unless Ubuntu ships signed modules for them, or
+/// DKMS is known to rebuild the driver for new kernels and the user asked to
+/// keep receiving them.
+fn protect_driver_from_kernel_updates(
+    driver_source: DriverSource,
+    installer_options: &InstallerOptions,
+) -> io::Result<()> {
+    if driver_source == DriverSource::UbuntuSigned {
+        println!("The signed modules metapackage follows kernel updates, leaving them unheld.");
+        return unlock_kernel_updates_debian();
+    }
+    if installer_options.unhold_kernel {
+        if dkms::covers_new_kernels()? {
+            println!("DKMS rebuilds the driver for new kernels, leaving kernel updates unheld.");
+            return unlock_kernel_updates_debian();
+        }
+        println!("DKMS isn't set up to rebuild the driver for new kernels, holding them instead.");
+    }
+
+    lock_kernel_updates_debian()
+}
+
+/// Installs the driver bundled in the CUDA runfile. Returns false when the
+/// modules were signed with a key that still has to be enrolled, in which
+/// case they can't be loaded before the next boot.
+fn install_driver_runfile(
+    cuda_config: &CudaConfig,
+    installer_options: &InstallerOptions,
+    kernel_module_type: KernelModuleType,
+) -> io::Result<bool> {
+    let sign_modules = installer_options.sign_modules || secure_boot::secure_boot_enabled()?;
+    if !sign_modules {
+        let installer_path = download_cuda_toolkit_installer(cuda_config)?;
+        let installer = installer_path.to_string_lossy().into_owned();
+        let mut args = vec![
+            installer,
+            String::from("--silent"),
+            String::from("--driver"),
+        ];
+        args.extend(installer_options.driver_args(kernel_module_type));
+        run_cmd("sh", args, CommandOptions::default())?;
+        return Ok(true);
+    }
+
+    run_cmd(
+        "apt-get",
+        ["install", "-y", "mokutil", "openssl"],
+        CommandOptions::default(),
+    )?;
+    let signing_key = secure_boot::ensure_signing_key()?;
+
+    // Only the standalone driver runfile knows how to sign its modules.
+    let temp_dir = TempDir::new()?;
+    let installer_path = extract_driver_installer(cuda_config, temp_dir.path())?;
+    let mut args = vec![
+        installer_path.to_string_lossy().into_owned(),
+        String::from("--silent"),
+        format!(
+            "--module-signing-secret-key={}",
+            signing_key.secret_key.display()
+        ),
+        format!(
+            "--module-signing-public-key={}",
+            signing_key.public_key.display()
+        ),
+    ];
+    // Under Secure Boot the kernel rejects modules signed with a key that
+    // isn't enrolled yet, which would fail the installer's test load.
+    let enrolled = secure_boot::signing_key_enrolled(&signing_key)?;
+    if !enrolled {
+        args.push(String::from("--skip-module-load"));
+    }
+    args.extend(installer_options.standalone_driver_args(kernel_module_type));
+    run_cmd("sh", args, CommandOptions::default())?;
+
+    if enrolled {
+        return Ok(true);
+    }
+    secure_boot::enroll_signing_key(&signing_key)?;
+    Ok(false)
+}
+
+/// Installs a pinned driver branch from NVIDIA's apt repository. The
+/// packages build their modules with DKMS, which also takes care of signing
+/// them with the distro's MOK key under Secure Boot.
+fn install_driver_apt(
+    cuda_config: &CudaConfig,
+    installer_options: &InstallerOptions,
+    kernel_module_type: KernelModuleType,
+) -> io::Result<()> {
+    match &installer_options.apt_repo {
+        Some(repo) => add_custom_apt_repo(repo, installer_options.apt_repo_key.as_deref())?,
+        None => add_nvidia_cuda_repo()?,
+    }
+
+    let branch = driver_branch(cuda_config, installer_options);
+    let package = apt_driver_package(&get_distro_id()?, &branch, kernel_module_type);
+
+    println!("Installing {package} from apt...");
+    run_cmd(
+        "apt-get",
+        ["install", "-y", package.as_str()],
+        CommandOptions::default(),
+    )?;
+    run_cmd(
+        "apt-mark",
+        ["hold", package.as_str()],
+        CommandOptions::default(),
+    )?;
+    ledger::record(LEDGER_DRIVER_PACKAGE, &package)?;
+    ledger::record(LEDGER_DRIVER_BRANCH, &branch)?;
+
+    Ok(())
+}
+
+fn apt_driver_package(
+    distro_id: &str,
+    branch: &str,
+    kernel_module_type: KernelModuleType,
+) -> String {
+    match (distro_id, kernel_module_type) {
+        ("ubuntu", KernelModuleType::Open) => format!("nvidia-driver-{branch}-open"),
+        ("ubuntu", _) => format!("nvidia-driver-{branch}"),
+        (_, KernelModuleType::Open) => format!("nvidia-open-{branch}"),
+        (_, _) => format!("cuda-drivers-{branch}"),
+    }
+}
+
+fn ubuntu_signed_driver_package(branch: &str, kernel_module_type: KernelModuleType) -> String {
+    let open = match kernel_module_type {
+        KernelModuleType::Open => "-open",
+        _ => "",
+    };
+    format!("nvidia-headless-no-dkms-{branch}-server{open}")
+}
+
+/// Installs the `linux-modules-nvidia-*` packages Ubuntu builds and signs
+/// for its cloud kernels, so there is nothing to compile or enroll. Returns
+/// false without changing anything when the running kernel has none.
+fn install_driver_ubuntu_signed(
+    cloud_provider: CloudProvider,
+    cuda_config: &CudaConfig,
+    installer_options: &InstallerOptions,
+    kernel_module_type: KernelModuleType,
+) -> io::Result<bool> {
+    if get_distro_id()? != "ubuntu" {
+        println!("Prebuilt signed NVIDIA modules are only available on Ubuntu.");
+        return Ok(false);
+    }
+
+    let kernel_version = get_kernel_version()?;
+    let kernel_suffix = cloud_provider.kernel_suffix("ubuntu");
+    if !kernel_version.ends_with(kernel_suffix) {
+        println!("Kernel {kernel_version} is not a {kernel_suffix} kernel.");
+        return Ok(false);
+    }
+
+    let branch = driver_branch(cuda_config, installer_options);
+    let open = match kernel_module_type {
+        KernelModuleType::Open => "-open",
+        _ => "",
+    };
+    let modules_package = format!("linux-modules-nvidia-{branch}-server{open}-{kernel_version}");
+    // The flavor metapackage keeps the modules in step with kernel updates.
+    let modules_metapackage = format!("linux-modules-nvidia-{branch}-server{open}{kernel_suffix}");
+    let driver_package = ubuntu_signed_driver_package(&branch, kernel_module_type);
+    let utils_package = format!("nvidia-utils-{branch}-server");
+
+    run_cmd("apt-get", ["update"], CommandOptions::default())?;
+    for package in [&modules_package, &modules_metapackage, &driver_package] {
+        if !apt_package_available(package)? {
+            println!("{package} is not available.");
+            return Ok(false);
+        }
+    }
+
+    println!("Installing prebuilt signed modules {modules_package}...");
+    run_cmd(
+        "apt-get",
+        [
+            "install",
+            "-y",
+            modules_package.as_str(),
+            modules_metapackage.as_str(),
+            driver_package.as_str(),
+            utils_package.as_str(),
+        ],
+        CommandOptions::default(),
+    )?;
+    run_cmd(
+        "apt-mark",
+        ["hold", driver_package.as_str(), utils_package.as_str()],
+        CommandOptions::default(),
+    )?;
+    // Everything installed above, so that uninstalling removes all of it.
+    let packages = [
+        &driver_package,
+        &utils_package,
+        &modules_package,
+        &modules_metapackage,
+    ];
+    ledger::record(
+        LEDGER_DRIVER_PACKAGE,
+        &packages.map(String::as_str).join(" "),
+    )?;
+    ledger::record(LEDGER_DRIVER_BRANCH, &branch)?;
+
+    Ok(true)
+}
+
+pub(crate) fn apt_package_available(package: &str) -> io::Result<bool> {
+    let output = run_cmd(
+        "apt-cache",
+        ["show", package],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    Ok(output.status.success() && !output.stdout.trim().is_empty())
+}
+
+fn driver_branch(cuda_config: &CudaConfig, installer_options: &InstallerOptions) -> String {
+    match &installer_options.driver_branch {
+        Some(branch) => branch.clone(),
+        None => cuda_config
+            .driver_version
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// Points apt at a mirror or a local repository (e.g. `file:/srv/nvidia`)
+/// laid out as a flat `./` repository.
+fn add_custom_apt_repo(repo: &str, key: Option<&str>) -> io::Result<()> {
+    let source = custom_apt_source(repo, key.map(|_| CUSTOM_APT_KEYRING))?;
+    if let Some(key) = key {
+        install_apt_key(key, CUSTOM_APT_KEYRING)?;
+    }
+    fs::write(CUSTOM_APT_SOURCE_FILENAME, source)?;
+    run_cmd("apt-get", ["update"], CommandOptions::default())?;
+    Ok(())
+}
+
+/// The sources.list line for a custom repository. Only local `file:`
+/// repositories may go unsigned, anything fetched over the network has to
+/// be checked against its key.
+fn custom_apt_source(repo: &str, keyring: Option<&str>) -> io::Result<String> {
+    match keyring {
+        Some(keyring) => Ok(format!("deb [signed-by={keyring}] {repo} ./\n")),
+        None if repo.starts_with("file:") => Ok(format!("deb [trusted=yes] {repo} ./\n")),
+        None => Err(io::Error::other(format!(
+            "{repo} is not a local file: repository, pass its signing key with --apt-repo-key"
+        ))),
+    }
+}
+
+/// Installs a key from a file or URL as a binary keyring for `signed-by`.
+fn install_apt_key(key: &str, keyring: &str) -> io::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key_path = if key.starts_with("https://") || key.starts_with("http://") {
+        let key_path = temp_dir.path().join("key");
+        let path = key_path.to_string_lossy().into_owned();
+        run_cmd(
+            "curl",
+            ["-fsSL", "-o", path.as_str(), key],
+            CommandOptions::default(),
+        )?;
+        key_path
+    } else {
+        PathBuf::from(key)
+    };
+
+    let content = fs::read(&key_path)?;
+    if content.starts_with(b"-----BEGIN PGP") {
+        let path = key_path.to_string_lossy().into_owned();
+        run_cmd(
+            "gpg",
+            [
+                "--batch",
+                "--yes",
+                "--dearmor",
+                "-o",
+                keyring,
+                path.as_str(),
+            ],
+            CommandOptions::default(),
+        )?;
+    } else {
+        fs::write(keyring, content)?;
+    }
+    Ok(())
+}
+
 pub(crate) fn uninstall_driver(cuda_version: CudaVersion) -> io::Result<()> {
     let cuda_config = CudaConfig::new(cuda_version);
 
@@ -151,33 +808,121 @@ pub(crate) fn uninstall_driver(cuda_version: CudaVersion) -> io::Result<()> {
         return Ok(());
     }
 
-    let temp_dir = TempDir::new()?;
-    let installer_path = download_cuda_toolkit_installer(&cuda_config)?;
+    fabric_manager::remove()?;
+    persistenced::remove()?;
+    if let Some(packages) = recorded_driver_packages() {
+        remove_driver_packages(&packages)?;
+        println!("Uninstallation completed!");
+        unlock_kernel_updates_debian()?;
+        return Ok(());
+    }
 
+    let temp_dir = TempDir::new()?;
     println!("Extracting NVIDIA driver installer, to complete uninstallation...");
+    let installer_path = extract_driver_installer(&cuda_config, temp_dir.path())?;
+
+    println!("Starting uninstallation...");
     let installer = installer_path.to_string_lossy().into_owned();
-    let extract_arg = format!("--extract={}", temp_dir.path().display());
     run_cmd(
         "sh",
-        [installer.as_str(), extract_arg.as_str()],
+        [installer.as_str(), "-s", "--uninstall"],
         CommandOptions::default(),
     )?;
 
-    let installer_path = temp_dir.path().join(format!(
-        "NVIDIA-Linux-x86_64-{}.run",
-        cuda_config.driver_version
-    ));
+    println!("Uninstallation completed!");
+    unlock_kernel_updates_debian()?;
 
-    println!("Starting uninstallation...");
+    Ok(())
+}
+
+/// Extracts the standalone `NVIDIA-Linux-x86_64-<version>.run` driver
+/// installer bundled in the CUDA runfile into `dest_dir`.
+fn extract_driver_installer(cuda_config: &CudaConfig, dest_dir: &Path) -> io::Result<PathBuf> {
+    let installer_path = download_cuda_toolkit_installer(cuda_config)?;
     let installer = installer_path.to_string_lossy().into_owned();
+    let extract_arg = format!("--extract={}", dest_dir.display());
     run_cmd(
         "sh",
-        [installer.as_str(), "-s", "--uninstall"],
+        [installer.as_str(), extract_arg.as_str()],
         CommandOptions::default(),
     )?;
 
-    println!("Uninstallation completed!");
-    unlock_kernel_updates_debian()?;
+    Ok(dest_dir.join(format!(
+        "NVIDIA-Linux-x86_64-{}.run",
+        cuda_config.driver_version
+    )))
+}
+
+/// Reports what a driver install on this machine will run into, without
+/// changing anything.
+pub(crate) fn preflight() -> io::Result<()> {
+    match pci::nvidia_gpus() {
+        Ok(gpus) if gpus.is_empty() => {
+            println!("[warn] No NVIDIA GPU found, only `install-cuda --toolkit-only` applies.")
+        }
+        Ok(gpus) => println!("[ok]   {} NVIDIA GPU(s) found on the PCI bus.", gpus.len()),
+        Err(err) => println!("[warn] Could not read the PCI bus: {err}"),
+    }
+
+    if Path::new(NOUVEAU_MODULE_DIR).exists() {
+        println!("[warn] nouveau is loaded, install-driver will blacklist it and may reboot.");
+    } else {
+        println!("[ok]   nouveau is not loaded.");
+    }
+
+    if secure_boot::secure_boot_enabled()? {
+        println!(
+            "[warn] Secure Boot is enabled, install-driver will sign the kernel modules and \
+            the signing key has to be enrolled from the console on the next boot."
+        );
+    } else {
+        println!("[ok]   Secure Boot is disabled, kernel modules don't need to be signed.");
+    }
+
+    Ok(())
+}
+
+/// Summarizes the driver, the active toolkit and what the ledger recorded.
+pub(crate) fn status() -> io::Result<()> {
+    match installed_driver_version()? {
+        Some(version) => {
+            let flavor = loaded_kernel_module_type()
+                .map(|flavor| format!(", {flavor} kernel modules"))
+                .unwrap_or_default();
+            let state = if verify_driver(false)? {
+                "working"
+            } else {
+                "NOT working"
+            };
+            println!("Driver:        {version}{flavor}, {state}");
+        }
+        None => println!("Driver:        not installed"),
+    }
+    if let Some(source) = ledger::get(LEDGER_DRIVER_SOURCE) {
+        println!("Installed from: {source}");
+    }
+
+    let kernel = get_kernel_version()?;
+    match ledger::get(LEDGER_DRIVER_KERNEL) {
+        Some(built_for) if built_for != kernel => {
+            println!("Kernel:        {kernel} (driver was installed on {built_for})")
+        }
+        _ => println!("Kernel:        {kernel}"),
+    }
+
+    match active_toolkit() {
+        Some(cuda_home) => println!("CUDA toolkit:  {}", cuda_home.display()),
+        None => println!("CUDA toolkit:  none active"),
+    }
+
+    if let Some(reason) = driver_guard::failure() {
+        println!();
+        println!(
+            "DRIVER GUARD FAILURE ({}):",
+            driver_guard::GUARD_FAILURE_MARKER
+        );
+        println!("  {reason}");
+    }
 
     Ok(())
 }
@@ -200,19 +945,42 @@ pub(crate) fn verify_driver(verbose: bool) -> io::Result<bool> {
         return Ok(false);
     }
 
-    let output = run_cmd(
-        "nvidia-smi",
-        ["-L"],
-        CommandOptions {
-            check: false,
-            silent: true,
-            ..Default::default()
-        },
-    )?;
-    let success = output.status.success() && output.stdout.contains("UUID");
+    // A GPU nvidia-smi can't query is a hardware problem, not a driver one,
+    // as long as the driver answers for the others.
+    let inventory = gpus::query_inventory();
+    let mut success = inventory
+        .as_ref()
+        .is_ok_and(|inventory| !inventory.gpus.is_empty());
 
     if verbose {
-        println!("nvidia-smi -L output: {} {}", output.stdout, output.stderr);
+        match &inventory {
+            Ok(inventory) => {
+                if inventory.gpus.is_empty() {
+                    println!("nvidia-smi works but reports no GPUs.");
+                }
+                for gpu in &inventory.gpus {
+                    println!("GPU {}: {} ({})", gpu.index, gpu.name, gpu.uuid);
+                }
+                for error in &inventory.errors {
+                    println!("nvidia-smi: {error}");
+                }
+            }
+            Err(err) => println!("{err}"),
+        }
+        if let Some(loaded) = loaded_kernel_module_type() {
+            println!("Loaded kernel modules: {loaded}");
+            match ledger::get(LEDGER_KERNEL_MODULE_TYPE) {
+                Some(recorded) if recorded != loaded.to_string() => println!(
+                    "Warning: {recorded} kernel modules were installed but {loaded} ones are loaded."
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    if success && fabric_manager::required() {
+        let driver_version = installed_driver_version()?.unwrap_or_default();
+        success = fabric_manager::verify(&driver_version, verbose)?;
     }
 
     Ok(success)
@@ -220,9 +988,18 @@ pub(crate) fn verify_driver(verbose: bool) -> io::Result<bool> {
 
 pub(crate) fn install_cuda(
     cloud_provider: CloudProvider,
-    cuda_version: CudaVersion,
+    mut command: InstallCudaCommand,
 ) -> io::Result<()> {
-    match install_cuda_inner(cloud_provider, cuda_version) {
+    if !command.toolkit_only && !has_nvidia_gpu() {
+        println!("No NVIDIA GPU found on the PCI bus, installing the CUDA toolkit only.");
+        command.toolkit_only = true;
+    }
+
+    if !command.toolkit_only && verify_driver(false)? {
+        ensure_driver_compatible(cloud_provider, &command)?;
+    }
+
+    match install_cuda_inner(cloud_provider, &command) {
         Ok(_) => Ok(()),
         Err(RebootRequired) => {
             reboot();
@@ -230,96 +1007,570 @@ pub(crate) fn install_cuda(
     }
 }
 
-pub(crate) fn install_nccl(command: InstallNcclCommand) -> io::Result<()> {
-    if command.install_dir.trim().is_empty() {
-        return Err(io::Error::other("install_dir cannot be empty"));
+pub(crate) fn list_toolkits() -> io::Result<()> {
+    let toolkits = installed_toolkits()?;
+    if toolkits.is_empty() {
+        println!(
+            "No CUDA toolkits found under {}.",
+            CUDA_TOOLKITS_DIRS.join(" or ")
+        );
+        return Ok(());
     }
 
+    let active = active_toolkit();
+    for toolkit in toolkits {
+        let marker = if active.as_deref() == Some(toolkit.path.as_path()) {
+            "*"
+        } else {
+            " "
+        };
+        println!(
+            "{marker} {:<10} {}",
+            toolkit.version,
+            toolkit.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+pub(crate) fn use_toolkit(version: &str) -> io::Result<()> {
+    let toolkit = find_toolkit(version)?;
+    activate_toolkit(&toolkit.path)?;
     println!(
-        "Installing NCCL {} to {}...",
-        NCCL_VERSION, command.install_dir
+        "CUDA {} at {} is now the active toolkit. Open a new shell to pick up {}.",
+        toolkit.version,
+        toolkit.path.display(),
+        PROFILE_FILENAME
     );
+    Ok(())
+}
 
-    let cuda_home = detect_cuda_home()?;
-    run_cmd(
-        "apt-get",
-        ["install", "-y", "build-essential"],
-        CommandOptions::default(),
-    )?;
+pub(crate) fn print_toolkit_env(version: &str) -> io::Result<()> {
+    let toolkit = find_toolkit(version)?;
+    for export in cuda_env_exports(&toolkit.path)? {
+        println!("{export}");
+    }
+    Ok(())
+}
 
-    let temp_dir = TempDir::new()?;
-    let archive_path = temp_dir.path().join(format!("nccl-{NCCL_VERSION}.tar.gz"));
-    let source_dir = temp_dir.path().join("src");
-    let archive = archive_path.to_string_lossy().into_owned();
-    let source = source_dir.to_string_lossy().into_owned();
+/// Makes sure the installed driver can run `command.version`, either by
+/// upgrading it or by installing cuda-compat when the user asked for that.
+fn ensure_driver_compatible(
+    cloud_provider: CloudProvider,
+    command: &InstallCudaCommand,
+) -> io::Result<()> {
+    let cuda_version = command.version;
+    let installed = installed_driver_version()?.ok_or_else(|| {
+        io::Error::other("The GPU driver is loaded but its version could not be determined")
+    })?;
+
+    let required = match driver_compatibility(&installed, cuda_version, command.strict_driver_check)
+    {
+        DriverCompatibility::Full => {
+            println!(
+                "Driver {installed} supports CUDA {cuda_version} (requires >= {}).",
+                cuda_version.minimum_driver_version()
+            );
+            return Ok(());
+        }
+        DriverCompatibility::MinorVersion => {
+            println!(
+                "Driver {installed} is older than {} but supports CUDA {cuda_version} through \
+                minor version compatibility (requires >= {}). PTX from this toolkit can't be \
+                JIT compiled, pass --strict-driver-check to require the newer driver.",
+                cuda_version.minimum_driver_version(),
+                cuda_version.minimum_compatible_driver_version()
+            );
+            return Ok(());
+        }
+        DriverCompatibility::Unsupported if command.strict_driver_check => {
+            cuda_version.minimum_driver_version()
+        }
+        DriverCompatibility::Unsupported => cuda_version.minimum_compatible_driver_version(),
+    };
+
+    if command.upgrade_driver {
+        println!("Driver {installed} is too old for CUDA {cuda_version}, upgrading it...");
+        remove_installed_driver()?;
+        install_driver(cloud_provider, cuda_version, &command.installer)?;
+
+        let upgraded = installed_driver_version()?.unwrap_or_default();
+        if version_key(&upgraded) < version_key(required) {
+            return Err(io::Error::other(format!(
+                "Driver upgrade did not succeed, found driver {upgraded:?} but CUDA \
+                {cuda_version} requires >= {required}"
+            )));
+        }
+        return Ok(());
+    }
+
+    if command.allow_forward_compat {
+        println!(
+            "Driver {installed} is older than {required}, installing the CUDA {cuda_version} \
+            forward compatibility package. Note that forward compatibility is only supported \
+            on data center GPUs."
+        );
+        return install_cuda_compat(cuda_version);
+    }
+
+    Err(io::Error::other(format!(
+        "CUDA {cuda_version} requires driver >= {required} but driver {installed} is installed. \
+        Re-run with --upgrade-driver to replace the driver, or --allow-forward-compat to \
+        install the cuda-compat package."
+    )))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DriverCompatibility {
+    /// At least the driver NVIDIA lists for the toolkit release
+    Full,
+    /// Same major version family, through CUDA minor version compatibility
+    MinorVersion,
+    Unsupported,
+}
+
+fn driver_compatibility(
+    installed: &str,
+    cuda_version: CudaVersion,
+    strict: bool,
+) -> DriverCompatibility {
+    if version_key(installed) >= version_key(cuda_version.minimum_driver_version()) {
+        DriverCompatibility::Full
+    } else if !strict
+        && version_key(installed) >= version_key(cuda_version.minimum_compatible_driver_version())
+    {
+        DriverCompatibility::MinorVersion
+    } else {
+        DriverCompatibility::Unsupported
+    }
+}
+
+/// Reads the loaded driver version from the kernel module, falling back to
+/// `nvidia-smi` when procfs is unavailable (e.g. inside containers).
+pub(crate) fn installed_driver_version() -> io::Result<Option<String>> {
+    if let Ok(content) = fs::read_to_string(NVIDIA_DRIVER_VERSION_FILE) {
+        if let Some(version) = parse_proc_driver_version(&content) {
+            return Ok(Some(version));
+        }
+    }
 
-    fs::create_dir_all(&source_dir)?;
+    let output = run_cmd(
+        "nvidia-smi",
+        ["--query-gpu=driver_version", "--format=csv,noheader"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    );
+    Ok(output
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            output
+                .stdout
+                .lines()
+                .next()
+                .map(|line| line.trim().to_string())
+        })
+        .filter(|version| !version.is_empty()))
+}
+
+// The open modules identify as "NVIDIA UNIX Open Kernel Module for x86_64".
+pub(crate) fn loaded_kernel_module_type() -> Option<KernelModuleType> {
+    parse_kernel_module_type(&fs::read_to_string(NVIDIA_DRIVER_VERSION_FILE).ok()?)
+}
+
+pub(crate) fn parse_kernel_module_type(content: &str) -> Option<KernelModuleType> {
+    let line = content
+        .lines()
+        .find(|line| line.starts_with("NVRM version:"))?;
+    if line.contains("Open Kernel Module") {
+        Some(KernelModuleType::Open)
+    } else {
+        Some(KernelModuleType::Proprietary)
+    }
+}
+
+// Parses "NVRM version: NVIDIA UNIX x86_64 Kernel Module  550.54.14  Thu Feb 22 ..."
+pub(crate) fn parse_proc_driver_version(content: &str) -> Option<String> {
+    let line = content
+        .lines()
+        .find(|line| line.starts_with("NVRM version:"))?;
+    line.split_whitespace()
+        .find(|token| token.contains('.') && token.chars().all(|c| c.is_ascii_digit() || c == '.'))
+        .map(str::to_string)
+}
+
+/// Removes the currently installed driver without needing to know which
+/// version it is: packages recorded in the ledger are purged, runfile
+/// installs are removed with the uninstaller they ship.
+fn remove_installed_driver() -> io::Result<()> {
+    fabric_manager::remove()?;
+    persistenced::remove()?;
+    if let Some(packages) = recorded_driver_packages() {
+        remove_driver_packages(&packages)?;
+    } else if Path::new(NVIDIA_UNINSTALLER).exists() {
+        run_cmd(NVIDIA_UNINSTALLER, ["--silent"], CommandOptions::default())?;
+    } else {
+        return Err(io::Error::other(format!(
+            "{NVIDIA_UNINSTALLER} not found and no driver package was recorded, \
+            don't know how to remove the installed driver"
+        )));
+    }
+
+    for module in ["nvidia_uvm", "nvidia_drm", "nvidia_modeset", "nvidia"] {
+        run_cmd(
+            "modprobe",
+            ["-r", module],
+            CommandOptions {
+                check: false,
+                ..Default::default()
+            },
+        )?;
+    }
+
+    unlock_kernel_updates_debian()
+}
+
+fn recorded_driver_packages() -> Option<Vec<String>> {
+    ledger::get(LEDGER_DRIVER_PACKAGE)
+        .filter(|_| {
+            matches!(
+                ledger::get(LEDGER_DRIVER_SOURCE).as_deref(),
+                Some("apt" | "ubuntu-signed")
+            )
+        })
+        .map(|packages| packages.split_whitespace().map(str::to_string).collect())
+}
+
+fn remove_driver_packages(packages: &[String]) -> io::Result<()> {
+    println!("Removing {}...", packages.join(", "));
     run_cmd(
-        "curl",
-        ["-fsSL", "-o", archive.as_str(), NCCL_SOURCE_URL],
+        "apt-mark",
+        std::iter::once("unhold").chain(packages.iter().map(String::as_str)),
         CommandOptions::default(),
     )?;
     run_cmd(
-        "tar",
-        [
-            "-xzf",
-            archive.as_str(),
-            "-C",
-            source.as_str(),
-            "--strip-components=1",
-        ],
+        "apt-get",
+        ["purge", "-y"]
+            .into_iter()
+            .chain(packages.iter().map(String::as_str)),
         CommandOptions::default(),
     )?;
+    run_cmd("apt-get", ["autoremove", "-y"], CommandOptions::default())?;
+    Ok(())
+}
 
-    let current_dir = env::current_dir()?;
-    env::set_current_dir(&source_dir)?;
-    let build_result = (|| {
-        let jobs = std::thread::available_parallelism()
-            .map(|parallelism| parallelism.get())
-            .unwrap_or(1)
-            .to_string();
-        let cuda_home_arg = format!("CUDA_HOME={cuda_home}");
-        run_cmd(
-            "make",
-            ["-j", jobs.as_str(), "src.build", cuda_home_arg.as_str()],
-            CommandOptions::default(),
-        )
-    })();
-    env::set_current_dir(current_dir)?;
-    build_result?;
+/// Rebuilds the command line of the last driver install from the ledger.
+pub(crate) fn recorded_driver_install() -> Option<(CudaVersion, InstallerOptions)> {
+    let cuda_version = ledger::get(LEDGER_DRIVER_CUDA_VERSION)
+        .and_then(|version| CudaVersion::from_str(&version, true).ok())?;
+    let installer_options = InstallerOptions {
+        driver_source: ledger::get(LEDGER_DRIVER_SOURCE)
+            .and_then(|source| DriverSource::from_str(&source, true).ok())
+            .unwrap_or_default(),
+        kernel_module_type: ledger::get(LEDGER_KERNEL_MODULE_TYPE)
+            .and_then(|module_type| KernelModuleType::from_str(&module_type, true).ok())
+            .unwrap_or_default(),
+        driver_branch: ledger::get(LEDGER_DRIVER_BRANCH),
+        dkms: recorded_flag(LEDGER_DRIVER_DKMS),
+        sign_modules: recorded_flag(LEDGER_DRIVER_SIGN_MODULES),
+        no_drm: recorded_flag(LEDGER_DRIVER_NO_DRM),
+        no_opengl_libs: recorded_flag(LEDGER_DRIVER_NO_OPENGL_LIBS),
+        apt_repo: ledger::get(LEDGER_DRIVER_APT_REPO).filter(|repo| !repo.is_empty()),
+        apt_repo_key: ledger::get(LEDGER_DRIVER_APT_REPO_KEY).filter(|key| !key.is_empty()),
+        ..Default::default()
+    };
+    Some((cuda_version, installer_options))
+}
 
-    install_built_nccl(&source_dir.join("build"), &command.install_dir)?;
-    if command.write_profile {
-        configure_nccl_environment(&command.install_dir)?;
+/// Records the options a reinstall needs to rebuild the same driver, an
+/// empty value stands for an option that wasn't given.
+fn record_installer_options(installer_options: &InstallerOptions) -> io::Result<()> {
+    for (key, value) in [
+        (LEDGER_DRIVER_DKMS, installer_options.dkms),
+        (LEDGER_DRIVER_SIGN_MODULES, installer_options.sign_modules),
+        (LEDGER_DRIVER_NO_DRM, installer_options.no_drm),
+        (
+            LEDGER_DRIVER_NO_OPENGL_LIBS,
+            installer_options.no_opengl_libs,
+        ),
+    ] {
+        ledger::record(key, &value.to_string())?;
     }
-    verify_nccl_installation(&command.install_dir)?;
+    ledger::record(
+        LEDGER_DRIVER_APT_REPO,
+        installer_options.apt_repo.as_deref().unwrap_or_default(),
+    )?;
+    ledger::record(
+        LEDGER_DRIVER_APT_REPO_KEY,
+        installer_options
+            .apt_repo_key
+            .as_deref()
+            .unwrap_or_default(),
+    )
+}
 
-    println!(
-        "NCCL {} installed successfully to {}.",
-        NCCL_VERSION, command.install_dir
-    );
-    if command.write_profile {
-        println!("Wrote {}", NCCL_PROFILE_FILENAME);
+fn recorded_flag(key: &str) -> bool {
+    ledger::get(key).as_deref() == Some("true")
+}
+
+/// Finds the CUDA version whose runfile bundles `driver`, given either as
+/// a branch (`580`) or a full version (`580.82.07`).
+fn cuda_version_for_driver(driver: &str) -> Option<CudaVersion> {
+    CudaVersion::value_variants()
+        .iter()
+        .copied()
+        .find(|version| {
+            let bundled = CudaConfig::new(*version).driver_version;
+            bundled == driver || bundled.starts_with(&format!("{driver}."))
+        })
+}
+
+pub(crate) fn upgrade_driver(
+    cloud_provider: CloudProvider,
+    mut command: UpgradeDriverCommand,
+) -> io::Result<()> {
+    let target = upgrade_target(&mut command)?;
+    let target_driver = describe_driver(target, &command.installer);
+
+    let current = installed_driver_version()?;
+    let up_to_date =
+        current
+            .as_deref()
+            .is_some_and(|current| match command.installer.driver_source {
+                DriverSource::Runfile => current == CudaConfig::new(target).driver_version,
+                _ => current == command.to || current.starts_with(&format!("{}.", command.to)),
+            });
+    if up_to_date {
+        println!("Driver {target_driver} is already installed.");
+        return Ok(());
     }
-    println!("Add the following to ~/.bashrc if you want NCCL on your default shell path:");
-    for export in nccl_env_exports(&command.install_dir) {
-        println!("{export}");
+
+    // What to go back to if the new driver doesn't work.
+    let previous = recorded_driver_install().or_else(|| {
+        current
+            .as_deref()
+            .and_then(cuda_version_for_driver)
+            .map(|version| (version, InstallerOptions::default()))
+    });
+
+    match &current {
+        Some(current) => {
+            println!("Replacing driver {current} with {target_driver}...");
+            remove_installed_driver()?;
+        }
+        None => println!("No driver is loaded, installing {target_driver}..."),
+    }
+
+    install_driver(cloud_provider, target, &command.installer)?;
+    if verify_driver(true)? {
+        println!("Driver {target_driver} installed successfully!");
+        // The active toolkit's cuda-compat libraries may no longer be needed.
+        if let Some(cuda_home) = active_toolkit() {
+            activate_toolkit(&cuda_home)?;
+        }
+        return Ok(());
+    }
+
+    // Signed modules can't load before their key is enrolled on the next
+    // boot, which doesn't make the new driver a failure.
+    if secure_boot::secure_boot_enabled()? && secure_boot::enrollment_pending()? {
+        println!(
+            "Driver {target_driver} is installed but can't load until its signing key is \
+            enrolled. Reboot, confirm the enrollment from the console and run \
+            `ignite cuda verify-driver`."
+        );
+        return Ok(());
+    }
+
+    let Some((previous_version, previous_options)) = previous else {
+        return Err(io::Error::other(format!(
+            "Driver {target_driver} doesn't work and the previous driver is unknown, \
+            can't roll back"
+        )));
+    };
+    let previous_driver = describe_driver(previous_version, &previous_options);
+    println!("Driver {target_driver} doesn't work, rolling back to {previous_driver}...");
+    remove_installed_driver()?;
+    install_driver(cloud_provider, previous_version, &previous_options)?;
+
+    if verify_driver(true)? {
+        Err(io::Error::other(format!(
+            "Driver {target_driver} didn't work, rolled back to {previous_driver}"
+        )))
+    } else {
+        Err(io::Error::other(format!(
+            "Driver {target_driver} didn't work and rolling back to {previous_driver} failed too"
+        )))
+    }
+}
+
+/// Checks that `--to` exists for the selected driver source and returns the
+/// CUDA version to install the driver for. The apt sources take the branch
+/// of `--to` from the repository, the runfile has to bundle it.
+fn upgrade_target(command: &mut UpgradeDriverCommand) -> io::Result<CudaVersion> {
+    let bundled = cuda_version_for_driver(&command.to);
+    let installer = &mut command.installer;
+    let available = CudaVersion::value_variants()
+        .iter()
+        .map(|version| CudaConfig::new(*version).driver_version)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if installer.driver_source == DriverSource::Runfile {
+        return bundled.ok_or_else(|| {
+            io::Error::other(format!(
+                "No CUDA release bundles driver {}. Available drivers: [{available}]",
+                command.to
+            ))
+        });
+    }
+
+    let branch = command.to.split('.').next().unwrap_or_default().to_string();
+    if branch.is_empty() || !branch.chars().all(|c| c.is_ascii_digit()) {
+        return Err(io::Error::other(format!(
+            "--to {} is neither a driver branch (580) nor a driver version (580.82.07)",
+            command.to
+        )));
     }
+    if let Some(driver_branch) = &installer.driver_branch {
+        if *driver_branch != branch {
+            return Err(io::Error::other(format!(
+                "--to {} and --driver-branch {driver_branch} disagree",
+                command.to
+            )));
+        }
+    }
+    installer.driver_branch = Some(branch.clone());
+    // The CUDA version only decides the runfile fallback, keep the current one.
+    let cuda_version = bundled
+        .or_else(|| recorded_driver_install().map(|(version, _)| version))
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "No CUDA version is recorded for the installed driver and no CUDA release \
+                bundles driver {}. With --driver-source {}, --to accepts any branch in the \
+                repository once a driver install is recorded, and otherwise one of: [{available}]",
+                command.to, installer.driver_source
+            ))
+        })?;
+
+    let kernel_module_type = installer.kernel_module_type.resolve()?;
+    let package = if installer.driver_source == DriverSource::Apt {
+        match &installer.apt_repo {
+            Some(repo) => add_custom_apt_repo(repo, installer.apt_repo_key.as_deref())?,
+            None => add_nvidia_cuda_repo()?,
+        }
+        apt_driver_package(&get_distro_id()?, &branch, kernel_module_type)
+    } else {
+        run_cmd("apt-get", ["update"], CommandOptions::default())?;
+        ubuntu_signed_driver_package(&branch, kernel_module_type)
+    };
+    if !apt_package_available(&package)? {
+        // ubuntu-signed falls back to the runfile, which needs to bundle it.
+        if installer.driver_source == DriverSource::Apt || bundled.is_none() {
+            return Err(io::Error::other(format!(
+                "Driver {} is not available from {}, {package} was not found",
+                command.to, installer.driver_source
+            )));
+        }
+    }
+
+    Ok(cuda_version)
+}
+
+fn describe_driver(cuda_version: CudaVersion, installer_options: &InstallerOptions) -> String {
+    match (
+        installer_options.driver_source,
+        &installer_options.driver_branch,
+    ) {
+        (DriverSource::Runfile, _) | (_, None) => CudaConfig::new(cuda_version).driver_version,
+        (_, Some(branch)) => format!("{branch} (from {})", installer_options.driver_source),
+    }
+}
+
+fn install_cuda_compat(cuda_version: CudaVersion) -> io::Result<()> {
+    add_nvidia_cuda_repo()?;
+    let package = format!("cuda-compat-{}", cuda_version.package_suffix());
+    run_cmd(
+        "apt-get",
+        ["install", "-y", package.as_str()],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+/// Adds NVIDIA's CUDA apt repository through its `cuda-keyring` package.
+pub(crate) fn add_nvidia_cuda_repo() -> io::Result<()> {
+    let distro = format!(
+        "{}{}",
+        get_distro_id()?,
+        get_distro_version_id()?.replace('.', "")
+    );
+    let keyring_url = format!(
+        "https://developer.download.nvidia.com/compute/cuda/repos/{distro}/x86_64/cuda-keyring_1.1-1_all.deb"
+    );
+
+    let temp_dir = TempDir::new()?;
+    let keyring_path = temp_dir.path().join("cuda-keyring.deb");
+    let keyring = keyring_path.to_string_lossy().into_owned();
+    run_cmd(
+        "curl",
+        ["-fsSL", "-o", keyring.as_str(), keyring_url.as_str()],
+        CommandOptions::default(),
+    )?;
+    run_cmd("dpkg", ["-i", keyring.as_str()], CommandOptions::default())?;
+    run_cmd("apt-get", ["update"], CommandOptions::default())?;
     Ok(())
 }
 
+/// Falls back to `true` when sysfs can't be read, so that a driver install is
+/// never skipped just because the PCI bus is hidden from us.
+fn has_nvidia_gpu() -> bool {
+    pci::nvidia_gpus()
+        .map(|gpus| !gpus.is_empty())
+        .unwrap_or(true)
+}
+
 fn install_cuda_inner(
     cloud_provider: CloudProvider,
-    cuda_version: CudaVersion,
+    command: &InstallCudaCommand,
 ) -> Result<(), RebootRequired> {
-    let cuda_config = CudaConfig::new(cuda_version);
+    let cuda_version = command.version;
+    let cuda_config = CudaConfig::new(cuda_version).with_prefix(command.prefix.as_deref());
 
-    if !verify_driver(false).unwrap_or(false) {
+    if !command.toolkit_only && !verify_driver(false).unwrap_or(false) {
         println!(
             "CUDA installation requires GPU driver to be installed first. \
             Attempting to install GPU driver now."
         );
-        install_driver(cloud_provider, cuda_version).unwrap();
+        install_driver(cloud_provider, cuda_version, &command.installer).unwrap();
+    }
+
+    let installs_nvcc =
+        command.components.is_empty() || command.components.iter().any(|c| c.trim() == "nvcc");
+    if command.toolkit_only && installs_nvcc {
+        // nvcc needs a host compiler, which install_driver would otherwise pull in.
+        run_cmd(
+            "apt-get",
+            ["install", "-y", "build-essential"],
+            CommandOptions::default(),
+        )
+        .unwrap();
+    }
+
+    if !command.components.is_empty() {
+        install_cuda_redist(&cuda_config, &command.components, &command.redist_url).unwrap();
+
+        println!("Executing post-installation actions...");
+        cuda_postinstallation_actions(&cuda_config).unwrap();
+        println!("CUDA post-installation actions completed!");
+
+        if Path::new(&format!("{}/nvcc", cuda_config.bin_folder)).exists() {
+            verify_nvcc(&cuda_config).unwrap();
+        }
+        return Ok(());
     }
 
     if Path::new(&format!("{}/nvcc", cuda_config.bin_folder)).exists() {
@@ -334,18 +1585,154 @@ fn install_cuda_inner(
 
     println!("Installing CUDA {} toolkit...", cuda_version);
     let installer = installer_path.to_string_lossy().into_owned();
-    run_cmd(
-        "sh",
-        [installer.as_str(), "--silent", "--toolkit"],
-        CommandOptions::default(),
-    )
-    .unwrap();
+    let toolkit_path = format!(
+        "--toolkitpath={}",
+        cuda_toolkit_home(&cuda_config).unwrap().display()
+    );
+    let mut args = vec![
+        installer,
+        String::from("--silent"),
+        String::from("--toolkit"),
+        toolkit_path,
+    ];
+    args.extend(command.installer.installer_args.iter().cloned());
+    run_cmd("sh", args, CommandOptions::default()).unwrap();
     println!("CUDA toolkit installation completed!");
 
     println!("Executing post-installation actions...");
     cuda_postinstallation_actions(&cuda_config).unwrap();
     println!("CUDA post-installation actions completed!");
 
+    verify_nvcc(&cuda_config).unwrap();
+
+    Ok(())
+}
+
+/// Installs individual components from NVIDIA's redistributable archives,
+/// as listed in `redistrib_<release>.json`, into the toolkit folder.
+fn install_cuda_redist(
+    cuda_config: &CudaConfig,
+    components: &[String],
+    redist_url: &str,
+) -> io::Result<()> {
+    let redist_url = redist_url.trim_end_matches('/');
+    let manifest_url = format!(
+        "{redist_url}/redistrib_{}.json",
+        cuda_config.version.release()
+    );
+    println!("Reading CUDA redistributable manifest {manifest_url} ...");
+    let manifest = fetch_json(&manifest_url)?;
+
+    let packages = resolve_redist_packages(&manifest, components)?;
+    let cuda_home = cuda_toolkit_home(cuda_config)?;
+    fs::create_dir_all(cuda_home)?;
+    let cuda_home_contents = format!("{}/", cuda_home.display());
+
+    for package in packages {
+        let archive_info = &manifest[package.as_str()][CUDA_REDIST_PLATFORM];
+        let (Some(relative_path), Some(sha256)) = (
+            archive_info["relative_path"].as_str(),
+            archive_info["sha256"].as_str(),
+        ) else {
+            return Err(io::Error::other(format!(
+                "{package} has no {CUDA_REDIST_PLATFORM} archive in {manifest_url}"
+            )));
+        };
+
+        println!(
+            "Installing {package} {}...",
+            manifest[package.as_str()]["version"].as_str().unwrap_or("")
+        );
+        let archive_path = download_file(
+            &format!("{redist_url}/{relative_path}"),
+            Checksum::Sha256(sha256),
+        )?;
+
+        let temp_dir = TempDir::new()?;
+        extract_archive(&archive_path, temp_dir.path())?;
+        let extracted = format!("{}/.", temp_dir.path().display());
+        run_cmd(
+            "cp",
+            ["-a", extracted.as_str(), cuda_home_contents.as_str()],
+            CommandOptions::default(),
+        )?;
+    }
+
+    // The archives use lib/ while the runfile layout, and everything that
+    // points at the toolkit, uses lib64/.
+    let lib64 = cuda_home.join("lib64");
+    if cuda_home.join("lib").is_dir() && !lib64.exists() {
+        std::os::unix::fs::symlink("lib", lib64)?;
+    }
+
+    Ok(())
+}
+
+/// Maps short component names (`nvcc`, `cublas`) to manifest packages
+/// (`cuda_nvcc`, `libcublas`), pulling in the packages nvcc needs to work.
+fn resolve_redist_packages(
+    manifest: &serde_json::Value,
+    components: &[String],
+) -> io::Result<Vec<String>> {
+    let mut packages = Vec::new();
+    for component in components {
+        let component = component.trim();
+        let package = [
+            component.to_string(),
+            format!("cuda_{component}"),
+            format!("lib{component}"),
+        ]
+        .into_iter()
+        .find(|candidate| manifest.get(candidate).is_some_and(|v| v.is_object()))
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "CUDA component {component} was not found in the redistributable manifest"
+            ))
+        })?;
+
+        if package == "cuda_nvcc" {
+            for dependency in ["cuda_crt", "libnvvm"] {
+                if manifest.get(dependency).is_some() && !packages.iter().any(|p| p == dependency) {
+                    packages.push(dependency.to_string());
+                }
+            }
+        }
+        if !packages.contains(&package) {
+            packages.push(package);
+        }
+    }
+
+    Ok(packages)
+}
+
+fn cuda_toolkit_home(cuda_config: &CudaConfig) -> io::Result<&Path> {
+    Path::new(&cuda_config.bin_folder)
+        .parent()
+        .ok_or_else(|| io::Error::other("CUDA bin folder has no parent directory"))
+}
+
+/// Compiles a trivial kernel, which works without a GPU or a driver.
+fn verify_nvcc(cuda_config: &CudaConfig) -> io::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source_path = temp_dir.path().join("ignite_check.cu");
+    let binary_path = temp_dir.path().join("ignite_check");
+    fs::write(
+        &source_path,
+        "__global__ void ignite_check(int *out) { *out = 42; }\n\
+        int main() { return 0; }\n",
+    )?;
+
+    let nvcc = format!("{}/nvcc", cuda_config.bin_folder);
+    let source = source_path.to_string_lossy().into_owned();
+    let binary = binary_path.to_string_lossy().into_owned();
+    run_cmd(
+        nvcc.as_str(),
+        ["-o", binary.as_str(), source.as_str()],
+        CommandOptions::default(),
+    )
+    .map_err(|_| io::Error::other(format!("{nvcc} failed to compile a test kernel")))?;
+
+    println!("Verified {nvcc} by compiling a test kernel.");
     Ok(())
 }
 
@@ -468,129 +1855,86 @@ fn install_dependencies_debian(cloud_provider: CloudProvider) -> Result<(), Rebo
     }
 }
 
-fn download_cuda_toolkit_installer(cuda_config: &CudaConfig) -> io::Result<PathBuf> {
-    println!(
-        "Downloading CUDA {} installation toolkit...",
-        cuda_config.version
-    );
-    download_file(&cuda_config.toolkit_url, &cuda_config.toolkit_checksum)
-}
-
-fn configure_persistanced_service() -> io::Result<()> {
-    if !Path::new("/usr/bin/nvidia-persistenced").exists() {
-        return Ok(());
-    }
-
-    if !Path::new(NVIDIA_PERSISTANCED_INSTALLER).exists() {
-        return Ok(());
-    }
-
-    let temp_dir = TempDir::new()?;
-    fs::copy(
-        NVIDIA_PERSISTANCED_INSTALLER,
-        temp_dir.path().join("installer.tar.bz2"),
-    )?;
-
-    let current_dir = env::current_dir()?;
-    env::set_current_dir(temp_dir.path())?;
-
-    run_cmd(
-        "tar",
-        ["-xf", "installer.tar.bz2"],
-        CommandOptions {
-            silent: true,
-            ..Default::default()
-        },
-    )?;
-    println!("Executing nvidia-persistenced installer...");
+/// Installs what building the driver needs for the running kernel only, so
+/// that nothing requires a reboot.
+fn install_build_dependencies_debian() -> io::Result<()> {
+    let kernel_version = get_kernel_version()?;
+    let headers_package = format!("linux-headers-{kernel_version}");
+    run_cmd("apt-get", ["update"], CommandOptions::default())?;
     run_cmd(
-        "sh",
-        ["nvidia-persistenced-init/install.sh"],
+        "apt-get",
+        [
+            "install",
+            "-y",
+            headers_package.as_str(),
+            "build-essential",
+            "dkms",
+            "pciutils",
+        ],
         CommandOptions::default(),
     )?;
-
-    env::set_current_dir(current_dir)?;
     Ok(())
 }
 
-fn install_built_nccl(build_dir: &Path, install_dir: &str) -> io::Result<()> {
-    let include_dir = build_dir.join("include");
-    let lib_dir = build_dir.join("lib");
-
-    if !include_dir.exists() || !lib_dir.exists() {
-        return Err(io::Error::other(format!(
-            "NCCL build output was missing include/ or lib/ under {}",
-            build_dir.display()
-        )));
-    }
-
-    let install_dir_path = Path::new(install_dir);
-    if install_dir_path.exists() {
-        if install_dir_path.is_dir() {
-            fs::remove_dir_all(install_dir_path)?;
-        } else {
-            fs::remove_file(install_dir_path)?;
-        }
+/// The NVIDIA installer refuses to run while nouveau is bound to the GPU.
+/// Blacklists it for future boots and tries to unload it right away, a
+/// reboot is only needed when something is still using it.
+fn disable_nouveau() -> Result<(), RebootRequired> {
+    if !Path::new(NOUVEAU_MODULE_DIR).exists() {
+        return Ok(());
     }
 
-    fs::create_dir_all(install_dir_path)?;
-
-    let include = include_dir.to_string_lossy().into_owned();
-    let lib = lib_dir.to_string_lossy().into_owned();
-    run_cmd(
-        "cp",
-        ["-a", include.as_str(), lib.as_str(), install_dir],
-        CommandOptions::default(),
-    )?;
-
+    println!("The nouveau driver is loaded, blacklisting it...");
     fs::write(
-        install_dir_path.join("VERSION"),
-        format!("NCCL {NCCL_VERSION}\n"),
-    )?;
-
-    Ok(())
-}
+        NOUVEAU_BLACKLIST_FILENAME,
+        "# Created by Spyral CUDA installation manager.\n\
+        blacklist nouveau\n\
+        options nouveau modeset=0\n",
+    )
+    .unwrap();
 
-fn configure_nccl_environment(install_dir: &str) -> io::Result<()> {
-    let mut profile = File::create(NCCL_PROFILE_FILENAME)?;
-    writeln!(
-        profile,
-        "# Configuring NCCL. File created by Spyral CUDA installation manager."
-    )?;
-    for export in nccl_env_exports(install_dir) {
-        writeln!(profile, "{export}")?;
+    if command_exists("update-initramfs").unwrap() {
+        run_cmd("update-initramfs", ["-u"], CommandOptions::default()).unwrap();
+    } else if command_exists("dracut").unwrap() {
+        run_cmd("dracut", ["--force"], CommandOptions::default()).unwrap();
+    } else {
+        println!(
+            "Neither update-initramfs nor dracut was found, nouveau may still be \
+            loaded from the initramfs."
+        );
     }
 
-    Ok(())
-}
-
-fn nccl_env_exports(install_dir: &str) -> [String; 4] {
-    [
-        format!("export NCCL_HOME={install_dir}"),
-        format!("export CPATH={install_dir}/include${{CPATH:+:${{CPATH}}}}"),
-        format!("export LIBRARY_PATH={install_dir}/lib${{LIBRARY_PATH:+:${{LIBRARY_PATH}}}}"),
-        format!(
-            "export LD_LIBRARY_PATH={install_dir}/lib${{LD_LIBRARY_PATH:+:${{LD_LIBRARY_PATH}}}}"
-        ),
-    ]
-}
-
-fn verify_nccl_installation(install_dir: &str) -> io::Result<()> {
-    let header_path = Path::new(install_dir).join("include/nccl.h");
-    let library_path = Path::new(install_dir).join("lib/libnccl.so");
+    run_cmd(
+        "modprobe",
+        ["-r", "nouveau"],
+        CommandOptions {
+            check: false,
+            ..Default::default()
+        },
+    )
+    .unwrap();
 
-    if !header_path.exists() || !library_path.exists() {
-        return Err(io::Error::other(format!(
-            "NCCL installation verification failed. Expected {} and {} to exist.",
-            header_path.display(),
-            library_path.display()
-        )));
+    if Path::new(NOUVEAU_MODULE_DIR).exists() {
+        println!("nouveau is still in use. System needs to reboot.");
+        Err(RebootRequired)
+    } else {
+        println!("nouveau unloaded, no reboot needed.");
+        Ok(())
     }
+}
 
-    Ok(())
+fn download_cuda_toolkit_installer(cuda_config: &CudaConfig) -> io::Result<PathBuf> {
+    println!(
+        "Downloading CUDA {} installation toolkit...",
+        cuda_config.version
+    );
+    download_file(
+        &cuda_config.toolkit_url,
+        Checksum::Md5(&cuda_config.toolkit_checksum),
+    )
 }
 
-fn detect_cuda_home() -> io::Result<String> {
+pub(crate) fn detect_cuda_home() -> io::Result<String> {
     let default_cuda = Path::new("/usr/local/cuda");
     if default_cuda.exists() {
         return Ok(default_cuda.display().to_string());
@@ -614,6 +1958,11 @@ fn detect_cuda_home() -> io::Result<String> {
 
     if let Ok(content) = fs::read_to_string(PROFILE_FILENAME) {
         for line in content.lines() {
+            if let Some(cuda_home) = line.strip_prefix("export CUDA_HOME=") {
+                if Path::new(cuda_home).exists() {
+                    return Ok(cuda_home.to_string());
+                }
+            }
             if let Some(path_export) = line.strip_prefix("export PATH=") {
                 let path_prefix = path_export.split("${").next().unwrap_or("");
                 if let Some(cuda_bin) = path_prefix.strip_suffix("/bin") {
@@ -626,26 +1975,196 @@ fn detect_cuda_home() -> io::Result<String> {
         }
     }
 
-    let mut cuda_dirs: Vec<String> = fs::read_dir("/usr/local")?
-        .filter_map(|entry| {
+    installed_toolkits()?
+        .pop()
+        .map(|toolkit| toolkit.path.display().to_string())
+        .ok_or_else(|| io::Error::other("Could not locate a CUDA installation"))
+}
+
+struct InstalledToolkit {
+    version: String,
+    path: PathBuf,
+}
+
+fn installed_toolkits() -> io::Result<Vec<InstalledToolkit>> {
+    let mut toolkits = Vec::new();
+    for dir in CUDA_TOOLKITS_DIRS {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        toolkits.extend(entries.filter_map(|entry| {
             let entry = entry.ok()?;
-            if !entry.path().is_dir() {
+            let name = entry.file_name().into_string().ok()?;
+            let version = name.strip_prefix("cuda-")?.to_string();
+            let path = entry.path();
+            if !is_toolkit_dir(&path) {
                 return None;
             }
+            Some(InstalledToolkit { version, path })
+        }));
+    }
+    toolkits.sort_by_key(|toolkit| version_key(&toolkit.version));
+    Ok(toolkits)
+}
 
-            let name = entry.file_name().into_string().ok()?;
-            if name.starts_with("cuda-") {
-                Some(format!("/usr/local/{name}"))
-            } else {
-                None
-            }
+/// Component installs may come without nvcc, e.g. only cudart and cublas.
+fn is_toolkit_dir(path: &Path) -> bool {
+    path.join("bin/nvcc").exists() || path.join("lib64").is_dir()
+}
+
+/// Reads the toolkit's release (`13.0.1`) from its `version.json`, falling
+/// back to the `cuda-X.Y` directory name for component installs without one.
+pub(crate) fn toolkit_version(cuda_home: &Path) -> Option<String> {
+    let version_json = fs::read_to_string(cuda_home.join("version.json"))
+        .ok()
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok());
+    if let Some(version) = version_json
+        .as_ref()
+        .and_then(|value| value["cuda"]["version"].as_str())
+    {
+        return Some(version.to_string());
+    }
+
+    let cuda_home = fs::canonicalize(cuda_home).ok()?;
+    let name = cuda_home.file_name()?.to_str()?;
+    name.strip_prefix("cuda-").map(str::to_string)
+}
+
+fn active_toolkit() -> Option<PathBuf> {
+    let target = fs::read_link(CUDA_SYMLINK).ok()?;
+    if target.is_absolute() {
+        Some(target)
+    } else {
+        Path::new(CUDA_SYMLINK).parent().map(|dir| dir.join(target))
+    }
+}
+
+/// Finds an installed toolkit either by its exact version (`12.8`) or by a
+/// shorter prefix (`12`) as long as only one installed toolkit matches it.
+fn find_toolkit(version: &str) -> io::Result<InstalledToolkit> {
+    let toolkits = installed_toolkits()?;
+    let installed = toolkits
+        .iter()
+        .map(|toolkit| toolkit.version.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut matches: Vec<InstalledToolkit> = toolkits
+        .into_iter()
+        .filter(|toolkit| {
+            toolkit.version == version
+                || toolkit.version.starts_with(&format!("{version}."))
+                || version.starts_with(&format!("{}.", toolkit.version))
         })
         .collect();
-    cuda_dirs.sort();
 
-    cuda_dirs
-        .pop()
-        .ok_or_else(|| io::Error::other("Could not locate a CUDA installation for building NCCL"))
+    if let Some(index) = matches
+        .iter()
+        .position(|toolkit| toolkit.version == version)
+    {
+        return Ok(matches.swap_remove(index));
+    }
+
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => Err(io::Error::other(format!(
+            "CUDA {version} is not installed. Installed toolkits: [{installed}]"
+        ))),
+        _ => Err(io::Error::other(format!(
+            "CUDA {version} is ambiguous. Installed toolkits: [{installed}]"
+        ))),
+    }
+}
+
+/// Matches a toolkit's release (`13.0.1`, or `13.0` from its directory name)
+/// to the CUDA version of the same major and minor release.
+fn toolkit_cuda_version(cuda_home: &Path) -> Option<CudaVersion> {
+    let version = toolkit_version(cuda_home)?;
+    let minor_release =
+        |release: &str| version_key(release).into_iter().take(2).collect::<Vec<_>>();
+    CudaVersion::value_variants()
+        .iter()
+        .copied()
+        .find(|cuda_version| minor_release(cuda_version.release()) == minor_release(&version))
+}
+
+/// `installed_driver` is the loaded driver's version, if there is one.
+fn cuda_library_dirs(cuda_home: &Path, installed_driver: Option<&str>) -> Vec<PathBuf> {
+    // The forward compatibility libcuda must win over an older driver's one,
+    // but it would shadow a driver that is new enough for the toolkit.
+    let compat_dir = cuda_home.join("compat");
+    let needs_compat = installed_driver.is_some_and(|driver| {
+        toolkit_cuda_version(cuda_home).is_some_and(|cuda_version| {
+            version_key(driver) < version_key(cuda_version.minimum_driver_version())
+        })
+    });
+    if needs_compat && compat_dir.is_dir() {
+        vec![compat_dir, cuda_home.join("lib64")]
+    } else {
+        vec![cuda_home.join("lib64")]
+    }
+}
+
+fn cuda_env_exports(cuda_home: &Path) -> io::Result<[String; 3]> {
+    let installed_driver = installed_driver_version()?;
+    let library_dirs = cuda_library_dirs(cuda_home, installed_driver.as_deref())
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect::<Vec<_>>()
+        .join(":");
+    let cuda_home = cuda_home.display();
+    Ok([
+        format!("export CUDA_HOME={cuda_home}"),
+        format!("export PATH={cuda_home}/bin${{PATH:+:${{PATH}}}}"),
+        format!("export LD_LIBRARY_PATH={library_dirs}${{LD_LIBRARY_PATH:+:${{LD_LIBRARY_PATH}}}}"),
+    ])
+}
+
+/// Makes `cuda_home` the system-wide toolkit. Every file is staged next to
+/// its destination first and then renamed into place, so a failure part way
+/// through never leaves a half-written profile or a dangling symlink.
+fn activate_toolkit(cuda_home: &Path) -> io::Result<()> {
+    if !is_toolkit_dir(cuda_home) {
+        return Err(io::Error::other(format!(
+            "{} does not contain a CUDA toolkit",
+            cuda_home.display()
+        )));
+    }
+
+    let symlink = Path::new(CUDA_SYMLINK);
+    if symlink.exists() && fs::symlink_metadata(symlink)?.is_dir() {
+        return Err(io::Error::other(format!(
+            "{CUDA_SYMLINK} is a directory rather than a symlink, refusing to replace it"
+        )));
+    }
+
+    let mut profile = String::from(
+        "# Configuring CUDA toolkit. File created by Spyral CUDA installation manager.\n",
+    );
+    for export in cuda_env_exports(cuda_home)? {
+        profile.push_str(&export);
+        profile.push('\n');
+    }
+    let ld_conf: String = cuda_library_dirs(cuda_home, installed_driver_version()?.as_deref())
+        .iter()
+        .map(|dir| format!("{}\n", dir.display()))
+        .collect();
+
+    let staged_symlink = PathBuf::from(format!("{CUDA_SYMLINK}.spyral-tmp"));
+    let staged_profile = PathBuf::from(format!("{PROFILE_FILENAME}.spyral-tmp"));
+    let staged_ld_conf = PathBuf::from(format!("{LD_CONF_FILENAME}.spyral-tmp"));
+
+    let _ = fs::remove_file(&staged_symlink);
+    std::os::unix::fs::symlink(cuda_home, &staged_symlink)?;
+    fs::write(&staged_profile, profile)?;
+    fs::write(&staged_ld_conf, ld_conf)?;
+
+    fs::rename(&staged_symlink, symlink)?;
+    fs::rename(&staged_profile, PROFILE_FILENAME)?;
+    fs::rename(&staged_ld_conf, LD_CONF_FILENAME)?;
+
+    run_cmd("ldconfig", [] as [&str; 0], CommandOptions::default())?;
+    Ok(())
 }
 
 fn cuda_postinstallation_actions(cuda_config: &CudaConfig) -> io::Result<()> {
@@ -668,23 +2187,285 @@ fn cuda_postinstallation_actions(cuda_config: &CudaConfig) -> io::Result<()> {
         env::set_var("LD_LIBRARY_PATH", &cuda_config.lib_folder);
     }
 
-    // Create profile file for persistent environment variables
-    let mut profile = File::create(PROFILE_FILENAME)?;
-    writeln!(
-        profile,
-        "# Configuring CUDA toolkit. File created by Spyral CUDA installation manager."
-    )?;
-    writeln!(
-        profile,
-        "export PATH={}${{PATH:+:${{PATH}}}}",
-        cuda_config.bin_folder
-    )?;
-    writeln!(
-        profile,
-        "export LD_LIBRARY_PATH={}${{LD_LIBRARY_PATH:+:${{LD_LIBRARY_PATH}}}}",
-        cuda_config.lib_folder
-    )?;
+    // The freshly installed toolkit becomes the active one, switch back
+    // with `ignite cuda use <version>` if needed.
+    activate_toolkit(cuda_toolkit_home(cuda_config)?)?;
 
-    configure_persistanced_service()?;
+    if persistenced::installed() {
+        persistenced::install_unless_configured()?;
+    } else {
+        println!("nvidia-persistenced is not installed, not setting up its unit.");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    #[test]
+    fn trusts_only_local_apt_repositories_without_a_key() {
+        assert_eq!(
+            custom_apt_source("file:/srv/nvidia", None).unwrap(),
+            "deb [trusted=yes] file:/srv/nvidia ./\n"
+        );
+        assert!(custom_apt_source("https://mirror.example.com/nvidia", None).is_err());
+        assert!(custom_apt_source("http://mirror.example.com/nvidia", None).is_err());
+        assert_eq!(
+            custom_apt_source(
+                "https://mirror.example.com/nvidia",
+                Some(CUSTOM_APT_KEYRING)
+            )
+            .unwrap(),
+            format!("deb [signed-by={CUSTOM_APT_KEYRING}] https://mirror.example.com/nvidia ./\n")
+        );
+    }
+
+    #[test]
+    fn picks_the_apt_driver_package_for_the_branch() {
+        let mut installer_options = InstallerOptions::default();
+        let cuda_config = CudaConfig::new(CudaVersion::V12_8);
+        let branch = driver_branch(&cuda_config, &installer_options);
+        assert_eq!(branch, "570");
+        installer_options.driver_branch = Some("580".to_string());
+        let branch = driver_branch(&cuda_config, &installer_options);
+        assert_eq!(branch, "580");
+
+        use KernelModuleType::*;
+        assert_eq!(
+            apt_driver_package("ubuntu", &branch, Open),
+            "nvidia-driver-580-open"
+        );
+        assert_eq!(
+            apt_driver_package("ubuntu", &branch, Proprietary),
+            "nvidia-driver-580"
+        );
+        assert_eq!(
+            apt_driver_package("debian", &branch, Open),
+            "nvidia-open-580"
+        );
+        assert_eq!(
+            apt_driver_package("debian", &branch, Proprietary),
+            "cuda-drivers-580"
+        );
+        assert_eq!(
+            ubuntu_signed_driver_package(&branch, Open),
+            "nvidia-headless-no-dkms-580-server-open"
+        );
+        assert_eq!(
+            ubuntu_signed_driver_package(&branch, Proprietary),
+            "nvidia-headless-no-dkms-580-server"
+        );
+    }
+
+    const REDIST_MANIFEST: &str = include_str!("../tests/fixtures/redistrib_12.8.0.json");
+
+    #[test]
+    fn resolves_redist_packages_from_the_manifest() {
+        let manifest: serde_json::Value = serde_json::from_str(REDIST_MANIFEST).unwrap();
+        let components = ["nvcc", "cudart", " cublas", "cuda_nvrtc", "cudart"].map(String::from);
+        assert_eq!(
+            resolve_redist_packages(&manifest, &components).unwrap(),
+            [
+                "cuda_crt",
+                "libnvvm",
+                "cuda_nvcc",
+                "cuda_cudart",
+                "libcublas",
+                "cuda_nvrtc"
+            ]
+        );
+
+        let err =
+            resolve_redist_packages(&manifest, &["cudart", "cudnn"].map(String::from)).unwrap_err();
+        assert!(err.to_string().contains("cudnn was not found"), "{err}");
+    }
+
+    /// Serves the fixture manifest and an archive for `package` from a file:
+    /// URL, the way `--redist-url` would point at a mirror.
+    fn redist_mirror(package: &str, sha256: Option<&str>) -> Option<(TempDir, String)> {
+        if !["curl", "tar", "xz", "sha256sum"]
+            .iter()
+            .all(|tool| command_exists(tool).unwrap_or(false))
+        {
+            eprintln!("Skipping, curl, tar, xz or sha256sum is not installed.");
+            return None;
+        }
+
+        let mirror = TempDir::new().unwrap();
+        let mut manifest: serde_json::Value = serde_json::from_str(REDIST_MANIFEST).unwrap();
+        let relative_path = manifest[package][CUDA_REDIST_PLATFORM]["relative_path"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let archive = mirror.path().join(&relative_path);
+        let top_dir = archive
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .trim_end_matches(".tar.xz")
+            .to_string();
+        let contents = mirror.path().join("contents");
+        fs::create_dir_all(contents.join(&top_dir).join("include")).unwrap();
+        fs::create_dir_all(contents.join(&top_dir).join("lib")).unwrap();
+        fs::write(
+            contents
+                .join(&top_dir)
+                .join("include")
+                .join(format!("{package}.h")),
+            "#pragma once\n",
+        )
+        .unwrap();
+        fs::create_dir_all(archive.parent().unwrap()).unwrap();
+        run_cmd(
+            "tar",
+            [
+                OsStr::new("-cJf"),
+                archive.as_os_str(),
+                OsStr::new("-C"),
+                contents.as_os_str(),
+                OsStr::new(&top_dir),
+            ],
+            CommandOptions {
+                silent: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        if let Some(sha256) = sha256 {
+            manifest[package][CUDA_REDIST_PLATFORM]["sha256"] = sha256.into();
+        } else {
+            let output = run_cmd(
+                "sha256sum",
+                [archive.as_os_str()],
+                CommandOptions {
+                    silent: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let actual = output.stdout.split_whitespace().next().unwrap().to_string();
+            manifest[package][CUDA_REDIST_PLATFORM]["sha256"] = actual.into();
+        }
+        fs::write(
+            mirror.path().join("redistrib_12.8.0.json"),
+            manifest.to_string(),
+        )
+        .unwrap();
+
+        // download_file keeps archives in /tmp, start without a cached copy.
+        let cached = Path::new("/tmp").join(archive.file_name().unwrap());
+        let _ = fs::remove_file(cached);
+        let url = format!("file://{}", mirror.path().display());
+        Some((mirror, url))
+    }
+
+    #[test]
+    fn installs_redist_components_into_the_prefix() {
+        let Some((mirror, url)) = redist_mirror("cuda_cudart", None) else {
+            return;
+        };
+        let prefix = mirror.path().join("cuda-12.8");
+        let prefix_str = prefix.to_string_lossy().into_owned();
+        let cuda_config = CudaConfig::new(CudaVersion::V12_8).with_prefix(Some(&prefix_str));
+
+        install_cuda_redist(&cuda_config, &["cudart".to_string()], &url).unwrap();
+        assert!(prefix.join("include/cuda_cudart.h").exists());
+        assert!(prefix.join("lib64").is_symlink());
+    }
+
+    #[test]
+    fn rejects_a_redist_archive_with_a_bad_checksum() {
+        let Some((mirror, url)) = redist_mirror("libcublas", Some(&"0".repeat(64))) else {
+            return;
+        };
+        let prefix = mirror.path().join("cuda-12.8");
+        let prefix_str = prefix.to_string_lossy().into_owned();
+        let cuda_config = CudaConfig::new(CudaVersion::V12_8).with_prefix(Some(&prefix_str));
+
+        let err = install_cuda_redist(&cuda_config, &["cublas".to_string()], &url).unwrap_err();
+        assert!(err.to_string().contains("checksum does not match"), "{err}");
+        assert!(!prefix.join("include").exists());
+    }
+
+    #[test]
+    fn accepts_older_drivers_through_minor_version_compatibility() {
+        use DriverCompatibility::*;
+
+        assert_eq!(
+            driver_compatibility("570.86.10", CudaVersion::V12_8, false),
+            Full
+        );
+        assert_eq!(
+            driver_compatibility("570.26", CudaVersion::V12_8, true),
+            Full
+        );
+        assert_eq!(
+            driver_compatibility("550.54.14", CudaVersion::V12_8, false),
+            MinorVersion
+        );
+        assert_eq!(
+            driver_compatibility("550.54.14", CudaVersion::V12_8, true),
+            Unsupported
+        );
+        assert_eq!(
+            driver_compatibility("525.60.13", CudaVersion::V12_5, false),
+            MinorVersion
+        );
+        assert_eq!(
+            driver_compatibility("520.61.05", CudaVersion::V12_5, false),
+            Unsupported
+        );
+        // CUDA 13 needs a 580 driver, a 12.x driver isn't enough.
+        assert_eq!(
+            driver_compatibility("575.57.08", CudaVersion::V13_0_1, false),
+            Unsupported
+        );
+        // 580.65.06 shipped with 13.0, the 13.0.1 runfile bundles 580.82.07.
+        assert_eq!(
+            driver_compatibility("580.65.06", CudaVersion::V13_0_1, false),
+            MinorVersion
+        );
+        assert_eq!(
+            driver_compatibility("580.82.07", CudaVersion::V13_0_1, true),
+            Full
+        );
+    }
+
+    #[test]
+    fn puts_cuda_compat_first_only_for_older_drivers() {
+        let cuda_home = TempDir::new().unwrap();
+        let cuda_home = cuda_home.path();
+        fs::write(
+            cuda_home.join("version.json"),
+            r#"{"cuda": {"name": "CUDA SDK", "version": "13.0.1"}}"#,
+        )
+        .unwrap();
+        let lib64 = cuda_home.join("lib64");
+        let compat = cuda_home.join("compat");
+
+        // Without cuda-compat installed there is nothing to put first.
+        assert_eq!(
+            cuda_library_dirs(cuda_home, Some("575.57.08")),
+            vec![lib64.clone()]
+        );
+
+        fs::create_dir(&compat).unwrap();
+        assert_eq!(
+            cuda_library_dirs(cuda_home, Some("575.57.08")),
+            vec![compat.clone(), lib64.clone()]
+        );
+        assert_eq!(
+            cuda_library_dirs(cuda_home, Some("580.65.06")),
+            vec![compat, lib64.clone()]
+        );
+        assert_eq!(
+            cuda_library_dirs(cuda_home, Some("580.82.07")),
+            vec![lib64.clone()]
+        );
+        assert_eq!(cuda_library_dirs(cuda_home, None), vec![lib64]);
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use tempfile::TempDir;
+
+use crate::{
+    install_cuda::{detect_cuda_home, toolkit_version, CUDA_REDIST_PLATFORM},
+    utils::{download_file, extract_archive, fetch_json, run_cmd, Checksum, CommandOptions},
+};
+
+const CUDNN_PROFILE_FILENAME: &str = "/etc/profile.d/spyral_cudnn.sh";
+const CUDNN_LD_CONF_FILENAME: &str = "/etc/ld.so.conf.d/spyral_cudnn.conf";
+const DEFAULT_CUDNN_VERSION: &str = "9.13.0";
+const DEFAULT_CUDNN_REDIST_URL: &str = "https://developer.download.nvidia.com/compute/cudnn/redist";
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct InstallCudnnCommand {
+    /// cuDNN release to install, as named by its `redistrib_<version>.json` manifest
+    #[arg(short, long, default_value = DEFAULT_CUDNN_VERSION)]
+    pub(crate) version: String,
+
+    /// Install into this directory, e.g. `/opt/cudnn`, instead of the active
+    /// CUDA toolkit
+    #[arg(long)]
+    pub(crate) install_dir: Option<String>,
+
+    /// Base URL of the cuDNN redistributable archives and their manifests
+    #[arg(long, default_value = DEFAULT_CUDNN_REDIST_URL)]
+    pub(crate) redist_url: String,
+}
+
+/// Installs the cuDNN build for the active toolkit's CUDA major version.
+pub(crate) fn install_cudnn(command: InstallCudnnCommand) -> io::Result<()> {
+    let cuda_home = PathBuf::from(detect_cuda_home()?);
+    let cuda_version = toolkit_version(&cuda_home).ok_or_else(|| {
+        io::Error::other(format!(
+            "Could not determine the CUDA version of {}",
+            cuda_home.display()
+        ))
+    })?;
+    let cuda_major = cuda_version.split('.').next().unwrap_or_default();
+
+    let redist_url = command.redist_url.trim_end_matches('/');
+    let manifest_url = format!("{redist_url}/redistrib_{}.json", command.version);
+    println!("Reading cuDNN redistributable manifest {manifest_url} ...");
+    let manifest = fetch_json(&manifest_url)?;
+
+    // cuDNN ships one archive per CUDA major version, keyed as `cuda12`, `cuda13`...
+    let variants = &manifest["cudnn"][CUDA_REDIST_PLATFORM];
+    let variant = format!("cuda{cuda_major}");
+    let archive_info = &variants[variant.as_str()];
+    let (Some(relative_path), Some(sha256)) = (
+        archive_info["relative_path"].as_str(),
+        archive_info["sha256"].as_str(),
+    ) else {
+        let available = variants
+            .as_object()
+            .map(|variants| variants.keys().cloned().collect::<Vec<_>>().join(", "))
+            .unwrap_or_default();
+        return Err(io::Error::other(format!(
+            "cuDNN {} has no {CUDA_REDIST_PLATFORM} build for CUDA {cuda_version}. \
+            Available builds: [{available}]",
+            command.version
+        )));
+    };
+
+    println!(
+        "Installing cuDNN {} for CUDA {cuda_version}...",
+        manifest["cudnn"]["version"]
+            .as_str()
+            .unwrap_or(&command.version)
+    );
+    let archive_path = download_file(
+        &format!("{redist_url}/{relative_path}"),
+        Checksum::Sha256(sha256),
+    )?;
+    let temp_dir = TempDir::new()?;
+    extract_archive(&archive_path, temp_dir.path())?;
+
+    // Inside the toolkit the libraries go next to the CUDA ones in lib64/, a
+    // separate install keeps the archive's own layout.
+    let (install_dir, lib_dir) = match &command.install_dir {
+        Some(install_dir) => {
+            let install_dir = PathBuf::from(install_dir);
+            (install_dir.clone(), install_dir.join("lib"))
+        }
+        None => (cuda_home.clone(), cuda_home.join("lib64")),
+    };
+    let include_dir = install_dir.join("include");
+    copy_dir_contents(&temp_dir.path().join("include"), &include_dir)?;
+    copy_dir_contents(&temp_dir.path().join("lib"), &lib_dir)?;
+
+    configure_cudnn_environment(&install_dir, &include_dir, &lib_dir)?;
+    verify_cudnn_installation(&include_dir, &lib_dir)?;
+
+    println!("cuDNN installed in {}", install_dir.display());
+    println!(
+        "Run `source {CUDNN_PROFILE_FILENAME}` or log in again to use it in the current shell."
+    );
+    Ok(())
+}
+
+fn copy_dir_contents(source: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    let source_contents = format!("{}/.", source.display());
+    let dest = format!("{}/", dest.display());
+    run_cmd(
+        "cp",
+        ["-a", source_contents.as_str(), dest.as_str()],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+fn configure_cudnn_environment(
+    install_dir: &Path,
+    include_dir: &Path,
+    lib_dir: &Path,
+) -> io::Result<()> {
+    let (install_dir, include_dir, lib_dir) = (
+        install_dir.display(),
+        include_dir.display(),
+        lib_dir.display(),
+    );
+
+    let mut profile = fs::File::create(CUDNN_PROFILE_FILENAME)?;
+    writeln!(
+        profile,
+        "# Configuring cuDNN. File created by Spyral CUDA installation manager."
+    )?;
+    writeln!(profile, "export CUDNN_HOME={install_dir}")?;
+    writeln!(profile, "export CPATH={include_dir}${{CPATH:+:${{CPATH}}}}")?;
+    writeln!(
+        profile,
+        "export LIBRARY_PATH={lib_dir}${{LIBRARY_PATH:+:${{LIBRARY_PATH}}}}"
+    )?;
+    writeln!(
+        profile,
+        "export LD_LIBRARY_PATH={lib_dir}${{LD_LIBRARY_PATH:+:${{LD_LIBRARY_PATH}}}}"
+    )?;
+    println!("Wrote {CUDNN_PROFILE_FILENAME}");
+
+    fs::write(CUDNN_LD_CONF_FILENAME, format!("{lib_dir}\n"))?;
+    run_cmd("ldconfig", [] as [&str; 0], CommandOptions::default())?;
+    Ok(())
+}
+
+fn verify_cudnn_installation(include_dir: &Path, lib_dir: &Path) -> io::Result<()> {
+    let header_path = include_dir.join("cudnn.h");
+    let library_path = lib_dir.join("libcudnn.so");
+
+    if !header_path.exists() || !library_path.exists() {
+        return Err(io::Error::other(format!(
+            "cuDNN installation verification failed. Expected {} and {} to exist.",
+            header_path.display(),
+            library_path.display()
+        )));
+    }
+
+    Ok(())
+}
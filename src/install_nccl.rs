@@ -0,0 +1,452 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use clap::Args;
+use tempfile::TempDir;
+
+use crate::{
+    gpus,
+    install_cuda::{detect_cuda_home, toolkit_version},
+    utils::*,
+};
+
+pub(crate) const NCCL_PROFILE_FILENAME: &str = "/etc/profile.d/spyral_nccl.sh";
+/// Starts the part of the NCCL profile owned by `install-nccl-net`, which
+/// rewriting the profile for a new NCCL build keeps.
+const NCCL_NET_PROFILE_MARKER: &str =
+    "# NCCL network plugin, written by `ignite cuda install-nccl-net`.";
+pub(crate) const DEFAULT_NCCL_INSTALL_DIR: &str = "/opt/nccl";
+const DEFAULT_NCCL_VERSION: &str = "2.30.3-1";
+const NCCL_SOURCE_URL: &str = "https://github.com/NVIDIA/nccl/archive/refs/tags";
+
+struct NcclRelease {
+    version: &'static str,
+    /// Oldest CUDA toolkit the release's notes list as supported
+    min_cuda: &'static str,
+    /// SHA-256 of the GitHub source tarball, None until someone records it
+    sha256: Option<&'static str>,
+}
+
+const NCCL_RELEASES: [NcclRelease; 3] = [
+    NcclRelease {
+        version: "2.30.3-1",
+        min_cuda: "12.0",
+        sha256: None,
+    },
+    NcclRelease {
+        version: "2.27.7-1",
+        min_cuda: "12.0",
+        sha256: None,
+    },
+    NcclRelease {
+        version: "2.21.5-1",
+        min_cuda: "11.0",
+        sha256: None,
+    },
+];
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct InstallNcclCommand {
+    /// NCCL release to build, one of the releases Ignite knows the checksum of
+    #[arg(long, default_value = DEFAULT_NCCL_VERSION)]
+    pub(crate) version: String,
+
+    /// SHA-256 of the source tarball, for releases whose checksum Ignite hasn't recorded
+    #[arg(long)]
+    pub(crate) sha256: Option<String>,
+
+    /// CUDA architectures to compile for, e.g. `90,100`, or `auto` for the local
+    /// GPUs' compute capabilities. Without it NCCL builds for every architecture
+    /// the toolkit supports, which takes a long time
+    #[arg(long)]
+    pub(crate) cuda_arch: Option<String>,
+
+    /// Installation directory for NCCL
+    #[arg(long, default_value = DEFAULT_NCCL_INSTALL_DIR)]
+    pub(crate) install_dir: String,
+
+    /// Write /etc/profile.d/spyral_nccl.sh for system-wide NCCL environment variables
+    #[arg(long)]
+    pub(crate) write_profile: bool,
+
+    /// Rebuild even when the installed NCCL already matches
+    #[arg(long)]
+    pub(crate) force: bool,
+}
+
+/// What the VERSION file of an NCCL install says it was built for.
+pub(crate) struct InstalledNccl {
+    pub(crate) version: String,
+    /// None for builds from before the toolkit was recorded
+    pub(crate) cuda_version: Option<String>,
+    /// None when NCCL was built for its default architecture list
+    pub(crate) cuda_archs: Option<Vec<String>>,
+}
+
+pub(crate) fn install_nccl(command: InstallNcclCommand) -> io::Result<()> {
+    if command.install_dir.trim().is_empty() {
+        return Err(io::Error::other("install_dir cannot be empty"));
+    }
+
+    let release = NCCL_RELEASES
+        .iter()
+        .find(|release| release.version == command.version)
+        .ok_or_else(|| {
+            let known: Vec<&str> = NCCL_RELEASES
+                .iter()
+                .map(|release| release.version)
+                .collect();
+            io::Error::other(format!(
+                "Unknown NCCL version {}, choose one of: {}",
+                command.version,
+                known.join(", ")
+            ))
+        })?;
+    let cuda_home = detect_cuda_home()?;
+    let cuda_version = toolkit_version(Path::new(&cuda_home)).ok_or_else(|| {
+        io::Error::other(format!(
+            "Could not determine the CUDA version in {cuda_home}"
+        ))
+    })?;
+    if version_key(&cuda_version) < version_key(release.min_cuda) {
+        return Err(io::Error::other(format!(
+            "NCCL {} requires CUDA {} or newer, but {cuda_home} is CUDA {cuda_version}",
+            release.version, release.min_cuda
+        )));
+    }
+
+    let cuda_archs = resolve_cuda_archs(command.cuda_arch.as_deref())?;
+    let installed = read_installed_version(&command.install_dir);
+    let covered = !command.force
+        && installed.as_ref().is_some_and(|installed| {
+            installed.version == release.version
+                && installed.cuda_version.as_deref() == Some(cuda_version.as_str())
+                && match (&installed.cuda_archs, &cuda_archs) {
+                    (None, _) => true,
+                    (Some(built), Some(wanted)) => wanted.iter().all(|arch| built.contains(arch)),
+                    (Some(_), None) => false,
+                }
+        });
+
+    if covered {
+        println!(
+            "NCCL {} in {} is built for CUDA {cuda_version} and already covers {}, \
+            skipping the build. Pass --force to rebuild it.",
+            release.version,
+            command.install_dir,
+            describe_archs(cuda_archs.as_deref())
+        );
+    } else {
+        println!(
+            "Installing NCCL {} for CUDA {cuda_version} and {} to {}...",
+            release.version,
+            describe_archs(cuda_archs.as_deref()),
+            command.install_dir
+        );
+        build_nccl(
+            release,
+            command.sha256.as_deref(),
+            &cuda_home,
+            &cuda_version,
+            cuda_archs.as_deref(),
+            &command.install_dir,
+        )?;
+    }
+
+    if command.write_profile {
+        configure_nccl_environment(&command.install_dir, &net_plugin_exports())?;
+    }
+    verify_nccl_installation(&command.install_dir)?;
+
+    println!(
+        "NCCL {} installed successfully to {}.",
+        release.version, command.install_dir
+    );
+    if command.write_profile {
+        println!("Wrote {}", NCCL_PROFILE_FILENAME);
+    }
+    println!("Add the following to ~/.bashrc if you want NCCL on your default shell path:");
+    for export in nccl_env_exports(&command.install_dir) {
+        println!("{export}");
+    }
+    Ok(())
+}
+
+fn build_nccl(
+    release: &NcclRelease,
+    sha256: Option<&str>,
+    cuda_home: &str,
+    cuda_version: &str,
+    cuda_archs: Option<&[String]>,
+    install_dir: &str,
+) -> io::Result<()> {
+    let source_url = format!("{NCCL_SOURCE_URL}/v{}.tar.gz", release.version);
+    let sha256 = sha256.or(release.sha256).ok_or_else(|| {
+        io::Error::other(format!(
+            "No checksum is recorded for NCCL {}. Check the tarball from a trusted machine \
+            with `curl -fsSL {source_url} | sha256sum` and pass the sum with --sha256",
+            release.version
+        ))
+    })?;
+
+    run_cmd(
+        "apt-get",
+        ["install", "-y", "build-essential"],
+        CommandOptions::default(),
+    )?;
+
+    // GitHub names the tarball after the bare tag, so keep it apart from
+    // other downloads in /tmp.
+    let archive_path = download_file_as(
+        &source_url,
+        &format!("nccl-{}.tar.gz", release.version),
+        Checksum::Sha256(sha256),
+    )?;
+    let temp_dir = TempDir::new()?;
+    let source_dir = temp_dir.path().join("src");
+    fs::create_dir_all(&source_dir)?;
+    extract_archive(&archive_path, &source_dir)?;
+
+    let current_dir = env::current_dir()?;
+    env::set_current_dir(&source_dir)?;
+    let build_result = {
+        let jobs = std::thread::available_parallelism()
+            .map(|parallelism| parallelism.get())
+            .unwrap_or(1)
+            .to_string();
+        let mut args = vec![
+            "-j".to_string(),
+            jobs,
+            "src.build".to_string(),
+            format!("CUDA_HOME={cuda_home}"),
+        ];
+        if let Some(cuda_archs) = cuda_archs {
+            args.push(format!("NVCC_GENCODE={}", nvcc_gencode(cuda_archs)));
+        }
+        run_cmd("make", &args, CommandOptions::default())
+    };
+    env::set_current_dir(current_dir)?;
+    build_result?;
+
+    install_built_nccl(
+        &source_dir.join("build"),
+        install_dir,
+        release.version,
+        cuda_version,
+        cuda_archs,
+    )
+}
+
+/// `auto` reads the local GPUs' compute capabilities, otherwise a comma
+/// separated list like `90,100` or `9.0,10.0`. None builds for everything.
+fn resolve_cuda_archs(cuda_arch: Option<&str>) -> io::Result<Option<Vec<String>>> {
+    let mut archs: Vec<String> = match cuda_arch {
+        None => return Ok(None),
+        Some("auto") => gpus::compute_capabilities()?,
+        Some(list) => list
+            .split(',')
+            .map(|arch| arch.trim().replace('.', ""))
+            .filter(|arch| !arch.is_empty())
+            .collect(),
+    };
+    if archs.is_empty() {
+        return Err(io::Error::other(
+            "No CUDA architectures to build for, pass them with --cuda-arch, e.g. 90,100",
+        ));
+    }
+    if let Some(invalid) = archs
+        .iter()
+        .find(|arch| !arch.chars().all(|c| c.is_ascii_digit()))
+    {
+        return Err(io::Error::other(format!(
+            "Invalid CUDA architecture {invalid}, expected a number like 90"
+        )));
+    }
+    archs.sort_by_key(|arch| arch.parse::<u32>().unwrap_or(0));
+    archs.dedup();
+    Ok(Some(archs))
+}
+
+// NCCL's makefiles pass NVCC_GENCODE straight to nvcc, one SASS target per
+// architecture is enough when the build is only for known GPUs.
+pub(crate) fn nvcc_gencode(cuda_archs: &[String]) -> String {
+    cuda_archs
+        .iter()
+        .map(|arch| format!("-gencode=arch=compute_{arch},code=sm_{arch}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn describe_archs(cuda_archs: Option<&[String]>) -> String {
+    match cuda_archs {
+        Some(archs) => format!("sm_{}", archs.join(", sm_")),
+        None => "all architectures".to_string(),
+    }
+}
+
+pub(crate) fn read_installed_version(install_dir: &str) -> Option<InstalledNccl> {
+    let content = fs::read_to_string(Path::new(install_dir).join("VERSION")).ok()?;
+    parse_installed_version(&content)
+}
+
+// VERSION holds the release, the toolkit and the architectures it was built for:
+//   NCCL 2.30.3-1
+//   CUDA 12.8
+//   CUDA_ARCH 90,100
+// where CUDA_ARCH is `all` for NCCL's default gencode list.
+fn parse_installed_version(content: &str) -> Option<InstalledNccl> {
+    let mut version = None;
+    let mut cuda_version = None;
+    let mut cuda_archs = None;
+    for line in content.lines() {
+        if let Some(value) = line.strip_prefix("NCCL ") {
+            version = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("CUDA ") {
+            cuda_version = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("CUDA_ARCH ") {
+            cuda_archs = match value.trim() {
+                "all" => None,
+                list => Some(list.split(',').map(str::to_string).collect()),
+            };
+        }
+    }
+    // Builds from before CUDA_ARCH was recorded used the default list.
+    Some(InstalledNccl {
+        version: version?,
+        cuda_version,
+        cuda_archs,
+    })
+}
+
+fn install_built_nccl(
+    build_dir: &Path,
+    install_dir: &str,
+    version: &str,
+    cuda_version: &str,
+    cuda_archs: Option<&[String]>,
+) -> io::Result<()> {
+    let include_dir = build_dir.join("include");
+    let lib_dir = build_dir.join("lib");
+
+    if !include_dir.exists() || !lib_dir.exists() {
+        return Err(io::Error::other(format!(
+            "NCCL build output was missing include/ or lib/ under {}",
+            build_dir.display()
+        )));
+    }
+
+    let install_dir_path = Path::new(install_dir);
+    if install_dir_path.exists() {
+        if install_dir_path.is_dir() {
+            fs::remove_dir_all(install_dir_path)?;
+        } else {
+            fs::remove_file(install_dir_path)?;
+        }
+    }
+
+    fs::create_dir_all(install_dir_path)?;
+
+    let include = include_dir.to_string_lossy().into_owned();
+    let lib = lib_dir.to_string_lossy().into_owned();
+    run_cmd(
+        "cp",
+        ["-a", include.as_str(), lib.as_str(), install_dir],
+        CommandOptions::default(),
+    )?;
+
+    let archs = cuda_archs.map_or("all".to_string(), |archs| archs.join(","));
+    fs::write(
+        install_dir_path.join("VERSION"),
+        format!("NCCL {version}\nCUDA {cuda_version}\nCUDA_ARCH {archs}\n"),
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn configure_nccl_environment(
+    install_dir: &str,
+    net_exports: &[String],
+) -> io::Result<()> {
+    let mut profile = File::create(NCCL_PROFILE_FILENAME)?;
+    writeln!(
+        profile,
+        "# Configuring NCCL. File created by Spyral CUDA installation manager."
+    )?;
+    for export in nccl_env_exports(install_dir) {
+        writeln!(profile, "{export}")?;
+    }
+    if !net_exports.is_empty() {
+        writeln!(profile, "{NCCL_NET_PROFILE_MARKER}")?;
+        for export in net_exports {
+            writeln!(profile, "{export}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The network plugin exports of the current NCCL profile, if any.
+fn net_plugin_exports() -> Vec<String> {
+    fs::read_to_string(NCCL_PROFILE_FILENAME)
+        .unwrap_or_default()
+        .lines()
+        .skip_while(|line| *line != NCCL_NET_PROFILE_MARKER)
+        .skip(1)
+        .map(str::to_string)
+        .collect()
+}
+
+pub(crate) fn nccl_env_exports(install_dir: &str) -> [String; 4] {
+    [
+        format!("export NCCL_HOME={install_dir}"),
+        format!("export CPATH={install_dir}/include${{CPATH:+:${{CPATH}}}}"),
+        format!("export LIBRARY_PATH={install_dir}/lib${{LIBRARY_PATH:+:${{LIBRARY_PATH}}}}"),
+        format!(
+            "export LD_LIBRARY_PATH={install_dir}/lib${{LD_LIBRARY_PATH:+:${{LD_LIBRARY_PATH}}}}"
+        ),
+    ]
+}
+
+pub(crate) fn verify_nccl_installation(install_dir: &str) -> io::Result<()> {
+    let header_path = Path::new(install_dir).join("include/nccl.h");
+    let library_path = Path::new(install_dir).join("lib/libnccl.so");
+
+    if !header_path.exists() || !library_path.exists() {
+        return Err(io::Error::other(format!(
+            "NCCL installation verification failed. Expected {} and {} to exist.",
+            header_path.display(),
+            library_path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_installed_version() {
+        let installed =
+            parse_installed_version("NCCL 2.30.3-1\nCUDA 12.8\nCUDA_ARCH 90,100\n").unwrap();
+        assert_eq!(installed.version, "2.30.3-1");
+        assert_eq!(installed.cuda_version.as_deref(), Some("12.8"));
+        assert_eq!(
+            installed.cuda_archs,
+            Some(vec!["90".to_string(), "100".to_string()])
+        );
+
+        // Older builds recorded neither the toolkit nor the architectures.
+        let installed = parse_installed_version("NCCL 2.27.7-1\n").unwrap();
+        assert_eq!(installed.version, "2.27.7-1");
+        assert_eq!(installed.cuda_version, None);
+        assert_eq!(installed.cuda_archs, None);
+
+        assert!(parse_installed_version("CUDA 12.8\n").is_none());
+    }
+}
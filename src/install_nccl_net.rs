@@ -0,0 +1,440 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use tempfile::TempDir;
+
+use crate::{
+    install_nccl::{
+        configure_nccl_environment, nccl_env_exports, verify_nccl_installation,
+        DEFAULT_NCCL_INSTALL_DIR, NCCL_PROFILE_FILENAME,
+    },
+    utils::*,
+    CloudProvider,
+};
+
+const EFA_INSTALLER_URL: &str = "https://efa-installer.amazonaws.com";
+/// Fingerprint of the key AWS signs the EFA installer with, as published in
+/// the EC2 user guide. The key file itself comes from the installer's host.
+const EFA_KEY_FINGERPRINT: &str = "4E9091BCBB97A96B26B15E59A05480B1DD2D3CCC";
+const EFA_HOME: &str = "/opt/amazon/efa";
+/// The EFA installer ships aws-ofi-nccl here
+const AWS_OFI_NCCL_HOME: &str = "/opt/amazon/ofi-nccl";
+const GCP_MACHINE_TYPE_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/machine-type";
+const GIB_HOME: &str = "/usr/local/gib";
+const GIB_INSTALLER_IMAGE: &str = "us-docker.pkg.dev/gce-ai-infra/gpudirect-gib/nccl-plugin-gib";
+const GIB_PLUGIN_VERSION: &str = "v1.0.6";
+const TCPXO_HOME: &str = "/var/lib/tcpxo";
+const TCPXO_INSTALLER_IMAGE: &str =
+    "us-docker.pkg.dev/gce-ai-infra/gpudirect-tcpxo/nccl-plugin-gpudirecttcpx-dev";
+const TCPXO_PLUGIN_VERSION: &str = "v1.0.8-1";
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct InstallNcclNetCommand {
+    /// NCCL installation the plugin is used with
+    #[arg(long, default_value = DEFAULT_NCCL_INSTALL_DIR)]
+    pub(crate) nccl_home: String,
+
+    /// Tag of Google's plugin installer image on GCP. Defaults to the tested
+    /// v1.0.8-1 for GPUDirect-TCPXO and v1.0.6 for gIB
+    #[arg(long)]
+    pub(crate) gcp_plugin_version: Option<String>,
+}
+
+enum NetPlugin {
+    /// aws-ofi-nccl over EFA, e.g. p5
+    AwsOfi,
+    /// GPUDirect-TCPXO, a3-mega
+    Tcpxo,
+    /// gIB over RDMA, a3-ultra and a4
+    Gib,
+}
+
+impl NetPlugin {
+    fn detect(cloud_provider: CloudProvider) -> io::Result<NetPlugin> {
+        match cloud_provider {
+            CloudProvider::Aws => Ok(NetPlugin::AwsOfi),
+            CloudProvider::Gcp => {
+                let machine_type = gcp_machine_type()?;
+                if machine_type.starts_with("a3-mega") {
+                    Ok(NetPlugin::Tcpxo)
+                } else if machine_type.starts_with("a3-ultra") || machine_type.starts_with("a4") {
+                    Ok(NetPlugin::Gib)
+                } else {
+                    Err(io::Error::other(format!(
+                        "{machine_type} has no GPUDirect NIC, NCCL uses its built-in socket transport"
+                    )))
+                }
+            }
+            CloudProvider::Azure => Err(io::Error::other(
+                "Azure GPU instances use InfiniBand, which NCCL supports without a plugin",
+            )),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            NetPlugin::AwsOfi => "aws-ofi-nccl",
+            NetPlugin::Tcpxo => "GPUDirect-TCPXO",
+            NetPlugin::Gib => "gIB",
+        }
+    }
+
+    /// Where the plugin's libraries end up.
+    fn home(&self) -> &'static str {
+        match self {
+            NetPlugin::AwsOfi => AWS_OFI_NCCL_HOME,
+            NetPlugin::Tcpxo => TCPXO_HOME,
+            NetPlugin::Gib => GIB_HOME,
+        }
+    }
+}
+
+pub(crate) fn install_nccl_net(
+    cloud_provider: CloudProvider,
+    command: InstallNcclNetCommand,
+) -> io::Result<()> {
+    verify_nccl_installation(&command.nccl_home)?;
+    let plugin = NetPlugin::detect(cloud_provider)?;
+    println!("Installing the {} NCCL network plugin...", plugin.name());
+
+    let mut library_dirs = Vec::new();
+    match plugin {
+        NetPlugin::AwsOfi => {
+            install_efa()?;
+            library_dirs.extend(library_dirs_under(Path::new(EFA_HOME)));
+        }
+        NetPlugin::Tcpxo => {
+            let version = command
+                .gcp_plugin_version
+                .as_deref()
+                .unwrap_or(TCPXO_PLUGIN_VERSION);
+            let image = format!("{TCPXO_INSTALLER_IMAGE}:{version}");
+            // The installer writes to /var/lib/tcpxo and probes the NICs.
+            run_plugin_installer(
+                &image,
+                &[
+                    "--network=host",
+                    "--cap-add=NET_ADMIN",
+                    "-v",
+                    "/var/lib:/var/lib",
+                ],
+            )?;
+        }
+        NetPlugin::Gib => {
+            let version = command
+                .gcp_plugin_version
+                .as_deref()
+                .unwrap_or(GIB_PLUGIN_VERSION);
+            let image = format!("{GIB_INSTALLER_IMAGE}:{version}");
+            let volume = format!("{GIB_HOME}:/var/lib/gib");
+            run_plugin_installer(&image, &["-v", volume.as_str()])?;
+        }
+    }
+
+    let library = find_plugin_library(Path::new(plugin.home())).ok_or_else(|| {
+        io::Error::other(format!(
+            "No libnccl-net*.so found under {} after installing {}",
+            plugin.home(),
+            plugin.name()
+        ))
+    })?;
+    let library_dir = library
+        .parent()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+    library_dirs.insert(0, library_dir);
+
+    let net_exports = vec![
+        format!("export NCCL_NET_PLUGIN={}", nccl_net_plugin_value(&library)),
+        format!(
+            "export LD_LIBRARY_PATH={}${{LD_LIBRARY_PATH:+:${{LD_LIBRARY_PATH}}}}",
+            library_dirs.join(":")
+        ),
+    ];
+    configure_nccl_environment(&command.nccl_home, &net_exports)?;
+
+    println!(
+        "{} installed, NCCL loads {}.",
+        plugin.name(),
+        library.display()
+    );
+    if matches!(plugin, NetPlugin::Tcpxo) {
+        println!("GPUDirect-TCPXO also needs Google's receive-datapath-manager container running on the host.");
+    }
+    println!("Wrote {NCCL_PROFILE_FILENAME}");
+    println!("Add the following to ~/.bashrc if you want NCCL and the plugin on your default shell path:");
+    for export in nccl_env_exports(&command.nccl_home)
+        .into_iter()
+        .chain(net_exports)
+    {
+        println!("{export}");
+    }
+    Ok(())
+}
+
+/// Runs AWS's EFA installer, which installs libfabric, the EFA kernel module
+/// and aws-ofi-nccl, and checks that libfabric sees the EFA device.
+fn install_efa() -> io::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let gnupg_home = temp_dir.path().join("gnupg");
+    let source_dir = temp_dir.path().join("aws-efa-installer");
+    fs::create_dir_all(&source_dir)?;
+    fs::create_dir_all(&gnupg_home)?;
+
+    let mut downloads = Vec::new();
+    for file in [
+        "aws-efa-installer-latest.tar.gz",
+        "aws-efa-installer-latest.tar.gz.sig",
+        "aws-efa-installer.key",
+    ] {
+        let path = temp_dir.path().join(file).to_string_lossy().into_owned();
+        let url = format!("{EFA_INSTALLER_URL}/{file}");
+        run_cmd(
+            "curl",
+            ["-fsSL", "-o", path.as_str(), url.as_str()],
+            CommandOptions {
+                retries: 2,
+                ..Default::default()
+            },
+        )?;
+        downloads.push(path);
+    }
+
+    // AWS signs the installer instead of publishing checksums. The key comes
+    // from the same host, so it is only trusted if it is the one AWS
+    // publishes the fingerprint of.
+    let gnupg_home = gnupg_home.to_string_lossy().into_owned();
+    let gpg_env = [("GNUPGHOME", gnupg_home.as_str())];
+    run_cmd(
+        "gpg",
+        ["--batch", "--import", downloads[2].as_str()],
+        CommandOptions {
+            env: &gpg_env,
+            ..Default::default()
+        },
+    )?;
+    let keys = run_cmd(
+        "gpg",
+        ["--batch", "--with-colons", "--list-keys"],
+        CommandOptions {
+            silent: true,
+            env: &gpg_env,
+            ..Default::default()
+        },
+    )?;
+    let fingerprints = primary_key_fingerprints(&keys.stdout);
+    if fingerprints != [EFA_KEY_FINGERPRINT] {
+        return Err(io::Error::other(format!(
+            "The EFA installer key has fingerprint(s) [{}] instead of {EFA_KEY_FINGERPRINT}. \
+            Won't continue installation.",
+            fingerprints.join(", ")
+        )));
+    }
+    let verification = run_cmd(
+        "gpg",
+        [
+            "--batch",
+            "--verify",
+            downloads[1].as_str(),
+            downloads[0].as_str(),
+        ],
+        CommandOptions {
+            check: false,
+            env: &gpg_env,
+            ..Default::default()
+        },
+    )?;
+    if !verification.status.success() {
+        return Err(io::Error::other(
+            "The EFA installer's signature does not verify. Won't continue installation.",
+        ));
+    }
+    extract_archive(Path::new(&downloads[0]), &source_dir)?;
+
+    // The installer script expects to run from its own directory.
+    let current_dir = env::current_dir()?;
+    env::set_current_dir(&source_dir)?;
+    let install_result = run_cmd("./efa_installer.sh", ["-y"], CommandOptions::default());
+    env::set_current_dir(current_dir)?;
+    install_result?;
+
+    let fi_info = format!("{EFA_HOME}/bin/fi_info");
+    let output = run_cmd(
+        &fi_info,
+        ["-p", "efa"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "`fi_info -p efa` found no EFA device, check that the instance has an EFA enabled interface: {}",
+            output.stderr.trim()
+        )));
+    }
+    println!("EFA installed, libfabric sees the EFA device.");
+    Ok(())
+}
+
+// `gpg --with-colons` prints each key's fingerprint on the `fpr` record
+// following its `pub` record, subkeys follow their own `sub` records:
+//   pub:-:4096:1:A05480B1DD2D3CCC:1561052216:::-:::scESC::::::23::0:
+//   fpr:::::::::4E9091BCBB97A96B26B15E59A05480B1DD2D3CCC:
+fn primary_key_fingerprints(colons: &str) -> Vec<String> {
+    let mut fingerprints = Vec::new();
+    let mut previous = "";
+    for line in colons.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields[0] == "fpr" && previous == "pub" {
+            if let Some(fingerprint) = fields.get(9) {
+                fingerprints.push(fingerprint.to_string());
+            }
+        }
+        previous = fields[0];
+    }
+    fingerprints
+}
+
+fn run_plugin_installer(image: &str, docker_args: &[&str]) -> io::Result<()> {
+    if !command_exists("docker")? {
+        return Err(io::Error::other(format!(
+            "Google installs its NCCL plugins from the {image} container, install Docker first"
+        )));
+    }
+    // Without --install-nccl the installer leaves NCCL alone, so the plugin
+    // is used with the NCCL installed by `install-nccl`.
+    let args: Vec<&str> = ["run", "--rm"]
+        .into_iter()
+        .chain(docker_args.iter().copied())
+        .chain([image, "install"])
+        .collect();
+    run_cmd(
+        "docker",
+        args,
+        CommandOptions {
+            retries: 2,
+            ..Default::default()
+        },
+    )?;
+    Ok(())
+}
+
+fn gcp_machine_type() -> io::Result<String> {
+    let output = run_cmd(
+        "curl",
+        [
+            "-fsS",
+            "-H",
+            "Metadata-Flavor: Google",
+            GCP_MACHINE_TYPE_URL,
+        ],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    // "projects/123456789/machineTypes/a3-megagpu-8g"
+    Ok(output
+        .stdout
+        .trim()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// The `lib*` directories of an installation, e.g. `lib` and `lib64`.
+fn library_dirs_under(home: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(home) else {
+        return Vec::new();
+    };
+    let mut dirs: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("lib"))
+        })
+        .map(|path| path.display().to_string())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// Plugins live in `lib`, `lib64` or, for Debian packages, a multiarch
+/// directory below `lib`.
+fn find_plugin_library(home: &Path) -> Option<PathBuf> {
+    let library_dirs = library_dirs_under(home);
+    let candidates = library_dirs
+        .iter()
+        .map(PathBuf::from)
+        .chain(library_dirs.iter().flat_map(|dir| {
+            fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+        }));
+    for dir in candidates {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut libraries: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name().is_some_and(|name| {
+                    let name = name.to_string_lossy();
+                    name.starts_with("libnccl-net") && name.ends_with(".so")
+                })
+            })
+            .collect();
+        // Prefer a named plugin like libnccl-net-ofi.so over a compatibility
+        // libnccl-net.so next to it.
+        libraries.sort_by_key(|path| path.ends_with("libnccl-net.so"));
+        if let Some(library) = libraries.into_iter().next() {
+            return Some(library);
+        }
+    }
+    None
+}
+
+// NCCL_NET_PLUGIN=ofi makes NCCL load libnccl-net-ofi.so. A plain
+// libnccl-net.so has no suffix to name, so it is named by its path.
+fn nccl_net_plugin_value(library: &Path) -> String {
+    library
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .and_then(|name| {
+            name.strip_prefix("libnccl-net-")?
+                .strip_suffix(".so")
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| library.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_primary_key_fingerprints() {
+        let colons = "tru::1:1700000000:0:3:1:5\n\
+            pub:-:4096:1:A05480B1DD2D3CCC:1561052216:::-:::scESC::::::23::0:\n\
+            fpr:::::::::4E9091BCBB97A96B26B15E59A05480B1DD2D3CCC:\n\
+            uid:-::::1561052216::0B4F6B2A3C6E4C0D2D1F1B5B0A9E7A1C2D3E4F50::Amazon EC2 EFA <ec2-efa-maintainers@amazon.com>::::::::::0:\n\
+            sub:-:4096:1:1F2E3D4C5B6A7988:1561052216::::::e::::::23:\n\
+            fpr:::::::::0123456789ABCDEF0123456789ABCDEF1F2E3D4C5B6A7988:\n";
+        assert_eq!(primary_key_fingerprints(colons), [EFA_KEY_FINGERPRINT]);
+        assert!(primary_key_fingerprints("").is_empty());
+    }
+}
@@ -0,0 +1,30 @@
+use std::{fs, io, path::Path};
+
+/// Records decisions made during installs (e.g. which kernel module flavor
+/// was installed) so that later runs and other commands can look them up.
+const LEDGER_FILENAME: &str = "/var/lib/ignite/ledger";
+
+pub(crate) fn get(key: &str) -> Option<String> {
+    let content = fs::read_to_string(LEDGER_FILENAME).ok()?;
+    content.lines().find_map(|line| {
+        let (line_key, value) = line.split_once('=')?;
+        (line_key == key).then(|| value.to_string())
+    })
+}
+
+pub(crate) fn record(key: &str, value: &str) -> io::Result<()> {
+    let content = fs::read_to_string(LEDGER_FILENAME).unwrap_or_default();
+    let mut lines: Vec<String> = content
+        .lines()
+        .filter(|line| line.split_once('=').map(|(k, _)| k) != Some(key))
+        .map(str::to_string)
+        .collect();
+    lines.push(format!("{key}={value}"));
+
+    if let Some(parent) = Path::new(LEDGER_FILENAME).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let staged = format!("{LEDGER_FILENAME}.tmp");
+    fs::write(&staged, lines.join("\n") + "\n")?;
+    fs::rename(staged, LEDGER_FILENAME)
+}
@@ -2,34 +2,78 @@ use std::io;
 
 use clap::{Parser, Subcommand, ValueEnum};
 
+pub(crate) mod dkms;
+pub(crate) mod doctor;
+pub(crate) mod driver_guard;
+pub(crate) mod fabric_manager;
+pub(crate) mod gpus;
+pub(crate) mod install_container_toolkit;
 pub(crate) mod install_cuda;
+pub(crate) mod install_cudnn;
+pub(crate) mod install_nccl;
+pub(crate) mod install_nccl_net;
 pub(crate) mod install_nvim;
 pub(crate) mod install_rust;
+pub(crate) mod ledger;
 pub(crate) mod mount;
+pub(crate) mod nccl_test;
+pub(crate) mod pci;
+pub(crate) mod persistenced;
+pub(crate) mod secure_boot;
+pub(crate) mod topology;
 pub(crate) mod utils;
+pub(crate) mod watch_xid;
+pub(crate) mod xid;
 
 use install_cuda::CudaVersion;
 
 fn main() -> io::Result<()> {
-    if !is_root() {
+    let args = Args::parse();
+
+    if args.command.requires_root() && !is_root() {
         eprintln!("This script needs to be run with root privileges!");
         std::process::exit(1);
     }
 
-    let args = Args::parse();
+    if is_root() && utils::is_resumed_run() {
+        // This is the run scheduled to continue after a reboot; remove the
+        // unit so it only runs once. Other commands leave it in place.
+        utils::clear_pending_resume()?;
+    }
 
     let home_dir = args.home_dir.unwrap_or("/home/ubuntu".to_string());
 
     match args.command {
         AppCommand::Cuda(cmd) => match cmd {
-            CudaCommand::InstallDriver { version } => {
-                install_cuda::install_driver(args.cloud_provider, version)?
+            CudaCommand::InstallDriver { version, installer } => {
+                install_cuda::install_driver(args.cloud_provider, version, &installer)?
+            }
+            CudaCommand::InstallCuda(cmd) => install_cuda::install_cuda(args.cloud_provider, cmd)?,
+            CudaCommand::InstallNccl(cmd) => install_nccl::install_nccl(cmd)?,
+            CudaCommand::InstallNcclNet(cmd) => {
+                install_nccl_net::install_nccl_net(args.cloud_provider, cmd)?
             }
-            CudaCommand::InstallCuda { version } => {
-                install_cuda::install_cuda(args.cloud_provider, version)?
+            CudaCommand::NcclTest(cmd) => nccl_test::nccl_test(cmd)?,
+            CudaCommand::InstallCudnn(cmd) => install_cudnn::install_cudnn(cmd)?,
+            CudaCommand::InstallContainerToolkit(cmd) => {
+                install_container_toolkit::install_container_toolkit(cmd)?
+            }
+            CudaCommand::UpgradeDriver(cmd) => {
+                install_cuda::upgrade_driver(args.cloud_provider, cmd)?
             }
-            CudaCommand::InstallNccl(cmd) => install_cuda::install_nccl(cmd)?,
             CudaCommand::UninstallDriver { version } => install_cuda::uninstall_driver(version)?,
+            CudaCommand::Dkms(cmd) => dkms::run_dkms_command(cmd)?,
+            CudaCommand::Guard(cmd) => driver_guard::run_guard_command(args.cloud_provider, cmd)?,
+            CudaCommand::Persistenced(cmd) => persistenced::run_persistenced_command(cmd)?,
+            CudaCommand::List => install_cuda::list_toolkits()?,
+            CudaCommand::Use { version } => install_cuda::use_toolkit(&version)?,
+            CudaCommand::Env { version } => install_cuda::print_toolkit_env(&version)?,
+            CudaCommand::Gpus { json, expect } => gpus::print_gpus(json, expect)?,
+            CudaCommand::Doctor => doctor::doctor()?,
+            CudaCommand::Topology => topology::print_topology()?,
+            CudaCommand::WatchXid(cmd) => {
+                watch_xid::run_watch_xid_command(args.cloud_provider, cmd)?
+            }
             CudaCommand::VerifyDriver => {
                 if install_cuda::verify_driver(true)? {
                     std::process::exit(0);
@@ -38,6 +82,8 @@ fn main() -> io::Result<()> {
                 }
             }
         },
+        AppCommand::Preflight => install_cuda::preflight()?,
+        AppCommand::Status => install_cuda::status()?,
         AppCommand::Nvim => install_nvim::install_nvim(home_dir)?,
         AppCommand::Rust => install_rust::install_rust(home_dir)?,
         AppCommand::Mount(cmd) => mount::configure_mount(cmd)?,
@@ -48,7 +94,10 @@ fn main() -> io::Result<()> {
             install_rust::install_rust(home_dir.clone())?;
 
             // This will install the driver first.
-            install_cuda::install_cuda(args.cloud_provider, cuda_version)?;
+            install_cuda::install_cuda(
+                args.cloud_provider,
+                install_cuda::InstallCudaCommand::from_version(cuda_version),
+            )?;
 
             println!("All components installed successfully!");
         }
@@ -100,6 +149,12 @@ enum AppCommand {
     #[command(subcommand)]
     Cuda(CudaCommand),
 
+    /// Check this machine for things that affect a GPU driver install
+    Preflight,
+
+    /// Show the state of the GPU driver and CUDA toolkit
+    Status,
+
     /// Install Neovim
     Nvim,
 
@@ -117,6 +172,25 @@ enum AppCommand {
     },
 }
 
+impl AppCommand {
+    /// Read-only commands can be run by any user, e.g. from a shell `eval`.
+    fn requires_root(&self) -> bool {
+        !matches!(
+            self,
+            AppCommand::Preflight
+                | AppCommand::Status
+                | AppCommand::Cuda(
+                    CudaCommand::List
+                        | CudaCommand::Env { .. }
+                        | CudaCommand::Gpus { .. }
+                        | CudaCommand::Topology
+                        | CudaCommand::Dkms(dkms::DkmsCommand::Status)
+                        | CudaCommand::Persistenced(persistenced::PersistencedCommand::Status)
+                )
+        )
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum CudaCommand {
     /// Install NVIDIA GPU driver
@@ -124,17 +198,31 @@ enum CudaCommand {
         /// CUDA version to install
         #[arg(short, long, value_enum, default_value = "v13-0-1")]
         version: CudaVersion,
+
+        #[command(flatten)]
+        installer: install_cuda::InstallerOptions,
     },
 
     /// Install CUDA toolkit
-    InstallCuda {
-        /// CUDA version to install
-        #[arg(short, long, value_enum, default_value = "v13-0-1")]
-        version: CudaVersion,
-    },
+    InstallCuda(install_cuda::InstallCudaCommand),
 
     /// Install NCCL
-    InstallNccl(install_cuda::InstallNcclCommand),
+    InstallNccl(install_nccl::InstallNcclCommand),
+
+    /// Install the cloud's NCCL network plugin, aws-ofi-nccl with EFA or Google's gIB/TCPXO
+    InstallNcclNet(install_nccl_net::InstallNcclNetCommand),
+
+    /// Build nccl-tests against the installed NCCL and run all_reduce_perf on the local GPUs
+    NcclTest(nccl_test::NcclTestCommand),
+
+    /// Install cuDNN for the active CUDA toolkit
+    InstallCudnn(install_cudnn::InstallCudnnCommand),
+
+    /// Install the NVIDIA Container Toolkit and configure Docker and/or containerd
+    InstallContainerToolkit(install_container_toolkit::InstallContainerToolkitCommand),
+
+    /// Replace the installed NVIDIA GPU driver, rolling back if the new one doesn't work
+    UpgradeDriver(install_cuda::UpgradeDriverCommand),
 
     /// Uninstall NVIDIA GPU driver
     UninstallDriver {
@@ -145,6 +233,53 @@ enum CudaCommand {
 
     /// Verify NVIDIA GPU driver installation
     VerifyDriver,
+
+    /// Check the driver and GPUs for known problems and suggest fixes
+    Doctor,
+
+    /// Show how GPUs, NICs and CPUs are connected and suggest process bindings
+    Topology,
+
+    /// Follow the kernel log and alert on GPU Xid errors
+    WatchXid(watch_xid::WatchXidCommand),
+
+    /// List the GPUs the driver sees
+    Gpus {
+        /// Print the inventory as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Fail unless exactly this many GPUs are found
+        #[arg(long)]
+        expect: Option<usize>,
+    },
+
+    /// Inspect or rebuild the DKMS registered NVIDIA driver
+    #[command(subcommand)]
+    Dkms(dkms::DkmsCommand),
+
+    /// Boot-time guard that repairs the driver after kernel changes
+    #[command(subcommand)]
+    Guard(driver_guard::GuardCommand),
+
+    /// Manage the nvidia-persistenced unit that keeps GPUs in persistence mode
+    #[command(subcommand)]
+    Persistenced(persistenced::PersistencedCommand),
+
+    /// List installed CUDA toolkits and mark the active one
+    List,
+
+    /// Make an installed CUDA toolkit the system-wide default
+    Use {
+        /// Installed toolkit version, for example `12.8` or `13.0`
+        version: String,
+    },
+
+    /// Print shell exports for a toolkit, use as `eval "$(ignite cuda env 12.8)"`
+    Env {
+        /// Installed toolkit version, for example `12.8` or `13.0`
+        version: String,
+    },
 }
 
 fn is_root() -> bool {
@@ -0,0 +1,229 @@
+use std::{env, io, path::Path};
+
+use clap::Args;
+use tempfile::TempDir;
+
+use crate::{
+    gpus,
+    install_cuda::detect_cuda_home,
+    install_nccl::{nvcc_gencode, read_installed_version, DEFAULT_NCCL_INSTALL_DIR},
+    utils::{command_exists, run_cmd, CommandOptions},
+};
+
+const NCCL_TESTS_REPO: &str = "https://github.com/NVIDIA/nccl-tests.git";
+/// nccl-tests release to build, so that a change on master can't change what
+/// the smoke test measures
+const NCCL_TESTS_TAG: &str = "v2.16.4";
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct NcclTestCommand {
+    /// NCCL installation to build and run the tests against
+    #[arg(long, default_value = DEFAULT_NCCL_INSTALL_DIR)]
+    pub(crate) nccl_home: String,
+
+    /// Fail when all_reduce_perf's peak bus bandwidth is below this many GB/s
+    #[arg(long)]
+    pub(crate) min_bus_bandwidth: Option<f64>,
+
+    /// Largest all_reduce_perf message size, e.g. 1G
+    #[arg(long, default_value = "1G")]
+    pub(crate) max_bytes: String,
+}
+
+pub(crate) fn nccl_test(command: NcclTestCommand) -> io::Result<()> {
+    let nccl_home = Path::new(&command.nccl_home);
+    if !nccl_home.join("include/nccl.h").exists() || !nccl_home.join("lib/libnccl.so").exists() {
+        return Err(io::Error::other(format!(
+            "No NCCL installation in {}, run `ignite cuda install-nccl` first",
+            command.nccl_home
+        )));
+    }
+    let cuda_home = detect_cuda_home()?;
+
+    run_cmd(
+        "apt-get",
+        ["install", "-y", "build-essential", "git"],
+        CommandOptions::default(),
+    )?;
+
+    let temp_dir = TempDir::new()?;
+    let source_dir = temp_dir.path().join("nccl-tests");
+    let source = source_dir.to_string_lossy().into_owned();
+    run_cmd(
+        "git",
+        [
+            "clone",
+            "--depth",
+            "1",
+            "--branch",
+            NCCL_TESTS_TAG,
+            NCCL_TESTS_REPO,
+            source.as_str(),
+        ],
+        CommandOptions {
+            retries: 2,
+            ..Default::default()
+        },
+    )?;
+    let commit = run_cmd(
+        "git",
+        ["-C", source.as_str(), "rev-parse", "HEAD"],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    println!(
+        "Building nccl-tests {NCCL_TESTS_TAG} ({})",
+        commit.stdout.trim()
+    );
+
+    let jobs = std::thread::available_parallelism()
+        .map(|parallelism| parallelism.get())
+        .unwrap_or(1)
+        .to_string();
+    let mut args = vec![
+        "-C".to_string(),
+        source.clone(),
+        "-j".to_string(),
+        jobs,
+        "MPI=0".to_string(),
+        format!("CUDA_HOME={cuda_home}"),
+        format!("NCCL_HOME={}", command.nccl_home),
+    ];
+    // Only build for what NCCL was built for, NCCL can't run on the others anyway.
+    if let Some(cuda_archs) =
+        read_installed_version(&command.nccl_home).and_then(|installed| installed.cuda_archs)
+    {
+        args.push(format!("NVCC_GENCODE={}", nvcc_gencode(&cuda_archs)));
+    }
+    run_cmd("make", &args, CommandOptions::default())?;
+
+    let all_reduce_perf = source_dir.join("build/all_reduce_perf");
+    if !all_reduce_perf.exists() {
+        return Err(io::Error::other(format!(
+            "nccl-tests build did not produce {}",
+            all_reduce_perf.display()
+        )));
+    }
+    println!(
+        "nccl-tests compiled and linked against NCCL in {}.",
+        command.nccl_home
+    );
+
+    let gpus = if command_exists("nvidia-smi")? {
+        gpus::inventory().unwrap_or_else(|err| {
+            println!("Could not list the GPUs: {err}");
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    if gpus.is_empty() {
+        println!("No GPUs found, skipping all_reduce_perf.");
+        return Ok(());
+    }
+
+    let ld_library_path = [
+        format!("{}/lib", command.nccl_home),
+        format!("{cuda_home}/lib64"),
+    ]
+    .into_iter()
+    .chain(env::var("LD_LIBRARY_PATH").ok())
+    .filter(|path| !path.is_empty())
+    .collect::<Vec<_>>()
+    .join(":");
+    let gpu_count = gpus.len().to_string();
+    let output = run_cmd(
+        &all_reduce_perf.to_string_lossy(),
+        [
+            "-b",
+            "8",
+            "-e",
+            command.max_bytes.as_str(),
+            "-f",
+            "2",
+            "-g",
+            gpu_count.as_str(),
+        ],
+        CommandOptions {
+            env: &[("LD_LIBRARY_PATH", ld_library_path.as_str())],
+            ..Default::default()
+        },
+    )?;
+
+    let peak = peak_bus_bandwidth(&output.stdout).ok_or_else(|| {
+        io::Error::other("Could not find the bus bandwidth in all_reduce_perf's output")
+    })?;
+    println!(
+        "all_reduce_perf across {} GPUs: peak bus bandwidth {peak:.2} GB/s",
+        gpus.len()
+    );
+
+    let Some(min_bus_bandwidth) = command.min_bus_bandwidth else {
+        return Ok(());
+    };
+    // The bus bandwidth of a single GPU all-reduce is 0 by definition.
+    if gpus.len() == 1 {
+        println!(
+            "Only one GPU, not checking the bus bandwidth against {min_bus_bandwidth:.2} GB/s."
+        );
+        return Ok(());
+    }
+    if peak < min_bus_bandwidth {
+        return Err(io::Error::other(format!(
+            "Peak bus bandwidth {peak:.2} GB/s is below the expected {min_bus_bandwidth:.2} GB/s"
+        )));
+    }
+    println!("Bus bandwidth meets the expected {min_bus_bandwidth:.2} GB/s.");
+    Ok(())
+}
+
+// Results are one row per message size, comments start with '#':
+//        size    count   type   redop    root     time   algbw   busbw #wrong     time   algbw   busbw #wrong
+//   8589934592  2147483648  float  sum  -1   46517  184.66  323.16      0   46421  185.04  323.83      0
+// The out-of-place and in-place busbw are the 8th and 12th columns.
+fn peak_bus_bandwidth(output: &str) -> Option<f64> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 13 || fields[0].parse::<u64>().is_err() {
+                return None;
+            }
+            let out_of_place = fields[7].parse::<f64>().ok()?;
+            let in_place = fields[11].parse::<f64>().ok()?;
+            Some(out_of_place.max(in_place))
+        })
+        .reduce(f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_peak_bus_bandwidth() {
+        let output = include_str!("../tests/fixtures/all-reduce-perf-8x-h100.txt");
+        assert_eq!(peak_bus_bandwidth(output), Some(418.97));
+    }
+
+    #[test]
+    fn single_gpu_bus_bandwidth_is_zero() {
+        let output = include_str!("../tests/fixtures/all-reduce-perf-1x-h100.txt");
+        assert_eq!(peak_bus_bandwidth(output), Some(0.0));
+    }
+
+    #[test]
+    fn finds_no_bus_bandwidth_without_results() {
+        // all_reduce_perf prints only its header when it fails to initialize.
+        let output = "# nThread 1 nGpus 8 minBytes 8 maxBytes 1073741824 step: 2(factor)\n\
+            #\n\
+            #       size         count      type   redop    root     time   algbw   busbw #wrong\n\
+            # Out of bounds values : 0 OK\n";
+        assert_eq!(peak_bus_bandwidth(output), None);
+        assert_eq!(peak_bus_bandwidth(""), None);
+    }
+}
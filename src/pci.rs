@@ -0,0 +1,105 @@
+use std::{fs, io, path::Path};
+
+const PCI_DEVICES_DIR: &str = "/sys/bus/pci/devices";
+pub(crate) const NVIDIA_VENDOR_ID: u16 = 0x10de;
+
+/// Display controllers (VGA and 3D) share the 0x03 PCI base class.
+const DISPLAY_CONTROLLER_CLASS: u32 = 0x03;
+
+/// NVSwitches show up as "other bridge" devices (class 0x0680).
+const OTHER_BRIDGE_CLASS: u32 = 0x0680;
+
+#[derive(Debug, Clone)]
+pub(crate) struct PciDevice {
+    /// Bus address, for example `0000:00:04.0`
+    pub(crate) address: String,
+    pub(crate) vendor: u16,
+    pub(crate) device: u16,
+    pub(crate) class: u32,
+}
+
+impl PciDevice {
+    pub(crate) fn is_display_controller(&self) -> bool {
+        self.class >> 16 == DISPLAY_CONTROLLER_CLASS
+    }
+
+    pub(crate) fn is_nvswitch(&self) -> bool {
+        self.vendor == NVIDIA_VENDOR_ID && self.class >> 8 == OTHER_BRIDGE_CLASS
+    }
+}
+
+pub(crate) fn list_devices() -> io::Result<Vec<PciDevice>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(PCI_DEVICES_DIR)? {
+        let entry = entry?;
+        let path = entry.path();
+        let address = entry.file_name().to_string_lossy().into_owned();
+
+        let (Some(vendor), Some(device), Some(class)) = (
+            read_hex_attribute(&path, "vendor"),
+            read_hex_attribute(&path, "device"),
+            read_hex_attribute(&path, "class"),
+        ) else {
+            continue;
+        };
+
+        devices.push(PciDevice {
+            address,
+            vendor: vendor as u16,
+            device: device as u16,
+            class,
+        });
+    }
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    Ok(devices)
+}
+
+pub(crate) fn nvidia_gpus() -> io::Result<Vec<PciDevice>> {
+    Ok(list_devices()?
+        .into_iter()
+        .filter(|device| device.vendor == NVIDIA_VENDOR_ID && device.is_display_controller())
+        .collect())
+}
+
+pub(crate) fn nvswitches() -> io::Result<Vec<PciDevice>> {
+    Ok(list_devices()?
+        .into_iter()
+        .filter(PciDevice::is_nvswitch)
+        .collect())
+}
+
+/// nvidia-smi prints bus ids with an 8 digit PCI domain (`00000000:04:00.0`),
+/// sysfs names the device with 4 (`0000:04:00.0`).
+pub(crate) fn sysfs_address(bus_id: &str) -> String {
+    let bus_id = bus_id.to_lowercase();
+    match bus_id.split_once(':') {
+        Some((domain, rest)) if domain.len() > 4 => {
+            format!("{}:{rest}", &domain[domain.len() - 4..])
+        }
+        _ => bus_id,
+    }
+}
+
+/// `None` on machines without NUMA, where sysfs reports -1.
+pub(crate) fn numa_node(address: &str) -> Option<u32> {
+    let content =
+        fs::read_to_string(Path::new(PCI_DEVICES_DIR).join(address).join("numa_node")).ok()?;
+    content.trim().parse().ok()
+}
+
+/// The CPUs close to the device, e.g. `0-51,104-155`.
+pub(crate) fn local_cpulist(address: &str) -> Option<String> {
+    let content = fs::read_to_string(
+        Path::new(PCI_DEVICES_DIR)
+            .join(address)
+            .join("local_cpulist"),
+    )
+    .ok()?;
+    Some(content.trim().to_string()).filter(|cpus| !cpus.is_empty())
+}
+
+// sysfs attributes look like "0x10de\n"
+fn read_hex_attribute(device_path: &Path, attribute: &str) -> Option<u32> {
+    let content = fs::read_to_string(device_path.join(attribute)).ok()?;
+    u32::from_str_radix(content.trim().trim_start_matches("0x"), 16).ok()
+}
@@ -0,0 +1,193 @@
+use std::{fs, io, path::Path};
+
+use clap::{Args, Subcommand};
+
+use crate::{
+    gpus,
+    utils::{run_cmd, systemd_quote, CommandOptions},
+};
+
+const PERSISTENCED_BINARY: &str = "/usr/bin/nvidia-persistenced";
+const PERSISTENCED_UNIT_NAME: &str = "nvidia-persistenced.service";
+// Takes precedence over the unit shipped with the driver packages.
+const PERSISTENCED_UNIT_PATH: &str = "/etc/systemd/system/nvidia-persistenced.service";
+const DEFAULT_PERSISTENCED_USER: &str = "nvidia-persistenced";
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum PersistencedCommand {
+    /// Write, enable and start the nvidia-persistenced unit
+    Install(PersistencedOptions),
+
+    /// Show whether persistence mode is on for each GPU
+    Status,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct PersistencedOptions {
+    /// User the daemon drops its privileges to, created if it doesn't exist
+    #[arg(long, default_value = DEFAULT_PERSISTENCED_USER)]
+    pub(crate) user: String,
+
+    /// Extra flag for nvidia-persistenced, e.g. `--verbose`. Can be repeated
+    #[arg(long = "option", allow_hyphen_values = true)]
+    pub(crate) options: Vec<String>,
+}
+
+impl Default for PersistencedOptions {
+    fn default() -> Self {
+        Self {
+            user: DEFAULT_PERSISTENCED_USER.to_string(),
+            options: Vec::new(),
+        }
+    }
+}
+
+pub(crate) fn run_persistenced_command(command: PersistencedCommand) -> io::Result<()> {
+    match command {
+        PersistencedCommand::Install(options) => install(&options),
+        PersistencedCommand::Status => {
+            report_persistence_mode()?;
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn installed() -> bool {
+    Path::new(PERSISTENCED_BINARY).exists()
+}
+
+/// Keeps the driver initialized while no process uses the GPUs, which saves
+/// seconds of start-up on every CUDA program and keeps nvidia-smi fast.
+pub(crate) fn install(options: &PersistencedOptions) -> io::Result<()> {
+    if !installed() {
+        return Err(io::Error::other(format!(
+            "{PERSISTENCED_BINARY} not found, install the driver first"
+        )));
+    }
+    ensure_user(&options.user)?;
+
+    let command_line = [PERSISTENCED_BINARY, "--user", options.user.as_str()]
+        .into_iter()
+        .chain(options.options.iter().map(String::as_str))
+        .map(systemd_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let unit = format!(
+        "[Unit]\n\
+        Description=NVIDIA Persistence Daemon\n\
+        After=systemd-modules-load.service ignite-driver-guard.service\n\
+        \n\
+        [Service]\n\
+        Type=forking\n\
+        ExecStart={command_line}\n\
+        ExecStopPost=/bin/rm -rf /var/run/nvidia-persistenced\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n"
+    );
+    fs::write(PERSISTENCED_UNIT_PATH, unit)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    run_cmd(
+        "systemctl",
+        ["enable", PERSISTENCED_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+    run_cmd(
+        "systemctl",
+        ["restart", PERSISTENCED_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+    println!("Installed {PERSISTENCED_UNIT_PATH}.");
+
+    if !report_persistence_mode()? {
+        println!(
+            "Warning: nvidia-persistenced is running but persistence mode is not on for every GPU."
+        );
+    }
+    Ok(())
+}
+
+/// Writes the unit with the default options, keeping one that already
+/// exists, e.g. from `ignite cuda persistenced install --user ...`.
+pub(crate) fn install_unless_configured() -> io::Result<()> {
+    if Path::new(PERSISTENCED_UNIT_PATH).exists() {
+        println!("{PERSISTENCED_UNIT_PATH} already exists, keeping it.");
+        return Ok(());
+    }
+    install(&PersistencedOptions::default())
+}
+
+/// Disables and removes the unit, which would otherwise keep pointing at
+/// the binary of a removed driver.
+pub(crate) fn remove() -> io::Result<()> {
+    if !Path::new(PERSISTENCED_UNIT_PATH).exists() {
+        return Ok(());
+    }
+
+    println!("Removing {PERSISTENCED_UNIT_PATH}...");
+    run_cmd(
+        "systemctl",
+        ["disable", "--now", PERSISTENCED_UNIT_NAME],
+        CommandOptions {
+            check: false,
+            ..Default::default()
+        },
+    )?;
+    fs::remove_file(PERSISTENCED_UNIT_PATH)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    Ok(())
+}
+
+fn ensure_user(user: &str) -> io::Result<()> {
+    let exists = run_cmd(
+        "id",
+        ["-u", user],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?
+    .status
+    .success();
+    if exists {
+        return Ok(());
+    }
+
+    run_cmd(
+        "useradd",
+        [
+            "--system",
+            "--no-create-home",
+            "--shell",
+            "/usr/sbin/nologin",
+            "--comment",
+            "NVIDIA Persistence Daemon",
+            user,
+        ],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+/// Prints the persistence mode of every GPU and returns whether it is on for
+/// all of them.
+pub(crate) fn report_persistence_mode() -> io::Result<bool> {
+    let gpus = match gpus::inventory() {
+        Ok(gpus) => gpus,
+        Err(err) => {
+            println!("Couldn't query the GPUs: {err}");
+            return Ok(false);
+        }
+    };
+
+    for gpu in &gpus {
+        println!(
+            "GPU {} ({}): persistence mode {}",
+            gpu.index,
+            gpu.pci_bus_id,
+            if gpu.persistence_mode { "on" } else { "off" }
+        );
+    }
+    Ok(gpus.iter().all(|gpu| gpu.persistence_mode))
+}
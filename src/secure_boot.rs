@@ -0,0 +1,174 @@
+use std::{
+    fs::{self, DirBuilder},
+    io::{self, Read},
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::utils::{command_exists, run_cmd, CommandOptions};
+
+const SECURE_BOOT_EFIVAR: &str =
+    "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+const SIGNING_KEY_DIR: &str = "/var/lib/ignite/mok";
+const SIGNING_KEY_SUBJECT: &str = "/CN=Spyral ignite kernel module signing key/";
+
+pub(crate) struct SigningKey {
+    pub(crate) secret_key: PathBuf,
+    pub(crate) public_key: PathBuf,
+}
+
+pub(crate) fn secure_boot_enabled() -> io::Result<bool> {
+    // The variable is 4 bytes of attributes followed by a single value byte.
+    if let Ok(content) = fs::read(SECURE_BOOT_EFIVAR) {
+        return Ok(content.last() == Some(&1));
+    }
+
+    if command_exists("mokutil")? {
+        let output = run_cmd(
+            "mokutil",
+            ["--sb-state"],
+            CommandOptions {
+                check: false,
+                silent: true,
+                ..Default::default()
+            },
+        )?;
+        return Ok(output.stdout.contains("SecureBoot enabled"));
+    }
+
+    // Legacy BIOS boots have no Secure Boot at all.
+    Ok(false)
+}
+
+/// Reuses the key pair from a previous install, so an already enrolled key
+/// keeps working across driver reinstalls.
+pub(crate) fn ensure_signing_key() -> io::Result<SigningKey> {
+    let key = SigningKey {
+        secret_key: Path::new(SIGNING_KEY_DIR).join("signing_key.priv"),
+        public_key: Path::new(SIGNING_KEY_DIR).join("signing_key.der"),
+    };
+    if key.secret_key.exists() && key.public_key.exists() {
+        println!("Reusing module signing key {}", key.public_key.display());
+        return Ok(key);
+    }
+
+    println!("Generating module signing key in {SIGNING_KEY_DIR}...");
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(SIGNING_KEY_DIR)?;
+    // The directory may predate this install with looser permissions.
+    fs::set_permissions(SIGNING_KEY_DIR, fs::Permissions::from_mode(0o700))?;
+    let secret_key = key.secret_key.to_string_lossy().into_owned();
+    let public_key = key.public_key.to_string_lossy().into_owned();
+    // openssl creates the private key with the process umask, so it must not
+    // be readable by others even for the moment before the chmod below.
+    let previous_umask = unsafe { libc::umask(0o077) };
+    let generated = run_cmd(
+        "openssl",
+        [
+            "req",
+            "-new",
+            "-x509",
+            "-newkey",
+            "rsa:2048",
+            "-nodes",
+            "-days",
+            "36500",
+            "-subj",
+            SIGNING_KEY_SUBJECT,
+            "-keyout",
+            secret_key.as_str(),
+            "-outform",
+            "DER",
+            "-out",
+            public_key.as_str(),
+        ],
+        CommandOptions::default(),
+    );
+    unsafe { libc::umask(previous_umask) };
+    generated?;
+    run_cmd(
+        "chmod",
+        ["600", secret_key.as_str()],
+        CommandOptions::default(),
+    )?;
+
+    Ok(key)
+}
+
+pub(crate) fn signing_key_enrolled(key: &SigningKey) -> io::Result<bool> {
+    let public_key = key.public_key.to_string_lossy().into_owned();
+    let output = run_cmd(
+        "mokutil",
+        ["--test-key", public_key.as_str()],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    Ok(output.stdout.contains("already enrolled"))
+}
+
+/// Whether a key queued with `mokutil --import` is still waiting to be
+/// confirmed on the next boot.
+pub(crate) fn enrollment_pending() -> io::Result<bool> {
+    if !command_exists("mokutil")? {
+        return Ok(false);
+    }
+    let output = run_cmd(
+        "mokutil",
+        ["--list-new"],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    // Without pending keys it prints "MokNew is empty" and fails.
+    Ok(output.status.success() && !output.stdout.trim().is_empty())
+}
+
+/// Queues the key for enrollment. MOK enrollment can only be confirmed from
+/// the firmware console on the next boot, so this prints the steps for it.
+pub(crate) fn enroll_signing_key(key: &SigningKey) -> io::Result<()> {
+    if signing_key_enrolled(key)? {
+        println!("Module signing key is already enrolled.");
+        return Ok(());
+    }
+
+    let password = enrollment_password()?;
+    let public_key = key.public_key.to_string_lossy().into_owned();
+    let input = format!("{password}\n{password}\n");
+    run_cmd(
+        "mokutil",
+        ["--import", public_key.as_str()],
+        CommandOptions {
+            input: Some(&input),
+            ..Default::default()
+        },
+    )?;
+
+    println!();
+    println!("The module signing key has been queued for enrollment.");
+    println!("The NVIDIA kernel modules won't load until it is enrolled:");
+    println!("  1. Reboot, with access to the machine's console (e.g. the serial console).");
+    println!("  2. In the blue MOK manager screen pick \"Enroll MOK\", then \"Continue\".");
+    println!("  3. Confirm with \"Yes\" and enter the one-time password: {password}");
+    println!("  4. Pick \"Reboot\", then run `ignite cuda verify-driver`.");
+    println!(
+        "If the console isn't reachable, add {} to the VM's Secure Boot signature database instead.",
+        key.public_key.display()
+    );
+    println!();
+
+    Ok(())
+}
+
+fn enrollment_password() -> io::Result<String> {
+    let mut buffer = [0u8; 4];
+    let mut urandom = fs::File::open("/dev/urandom")?;
+    urandom.read_exact(&mut buffer)?;
+    Ok(format!("{:08}", u32::from_le_bytes(buffer) % 100_000_000))
+}
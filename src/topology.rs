@@ -0,0 +1,327 @@
+use std::{collections::HashMap, io};
+
+use crate::{
+    gpus, pci,
+    utils::{run_cmd, CommandOptions},
+};
+
+/// `nvidia-smi topo -m` link types from the closest to the farthest.
+const LINK_TYPES: [&str; 6] = ["PIX", "PXB", "PHB", "NODE", "SYS", "X"];
+
+struct TopologyMatrix {
+    /// Column labels in order, e.g. `GPU0`, `GPU1`, `NIC0`
+    devices: Vec<String>,
+    /// Link type between two devices, keyed by their labels
+    links: HashMap<(String, String), String>,
+    /// The extra columns per row, e.g. `CPU Affinity` and `NUMA Affinity`
+    affinities: HashMap<(String, String), String>,
+    /// NIC labels to device names, e.g. `NIC0` to `mlx5_0`
+    nic_names: HashMap<String, String>,
+}
+
+impl TopologyMatrix {
+    fn link(&self, from: &str, to: &str) -> Option<&str> {
+        self.links
+            .get(&(from.to_string(), to.to_string()))
+            .map(String::as_str)
+    }
+
+    fn affinity(&self, device: &str, column: &str) -> Option<&str> {
+        self.affinities
+            .get(&(device.to_string(), column.to_string()))
+            .map(String::as_str)
+            .filter(|value| *value != "N/A")
+    }
+
+    fn labels<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> {
+        self.devices
+            .iter()
+            .filter(move |device| device.starts_with(prefix))
+    }
+
+    /// The NIC with the closest link to `gpu` and that link's type.
+    fn closest_nic(&self, gpu: &str) -> Option<(&str, &str)> {
+        self.labels("NIC")
+            .filter_map(|nic| Some((nic.as_str(), self.link(gpu, nic)?)))
+            .min_by_key(|(_, link)| link_rank(link))
+            .map(|(nic, link)| {
+                let name = self.nic_names.get(nic).map_or(nic, String::as_str);
+                (name, link)
+            })
+    }
+}
+
+/// NVLink (`NV18`, 18 bonded links) is the closest of all, more links is better.
+fn link_rank(link: &str) -> i64 {
+    if let Some(count) = link.strip_prefix("NV") {
+        return -count.parse::<i64>().unwrap_or(1);
+    }
+    LINK_TYPES
+        .iter()
+        .position(|link_type| *link_type == link)
+        .map_or(LINK_TYPES.len() as i64, |rank| rank as i64)
+}
+
+pub(crate) fn print_topology() -> io::Result<()> {
+    let output = run_cmd(
+        "nvidia-smi",
+        ["topo", "-m"],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    let topology = parse_topology(&output.stdout)?;
+    let gpus = gpus::inventory()?;
+    let gpu_labels: Vec<&String> = topology.labels("GPU").collect();
+
+    println!("GPU links:");
+    let header: String = gpu_labels
+        .iter()
+        .map(|label| format!("{label:8}"))
+        .collect();
+    println!("{:8}{}", "", header.trim_end());
+    for from in &gpu_labels {
+        let row: String = gpu_labels
+            .iter()
+            .map(|to| format!("{:8}", topology.link(from, to).unwrap_or("?")))
+            .collect();
+        println!("{from:8}{}", row.trim_end());
+    }
+    println!();
+
+    let mut bindings = Vec::new();
+    for gpu in &gpus {
+        let label = format!("GPU{}", gpu.index);
+        let address = pci::sysfs_address(&gpu.pci_bus_id);
+        let numa_node = pci::numa_node(&address).or_else(|| {
+            topology
+                .affinity(&label, "NUMA Affinity")
+                .and_then(|node| node.parse().ok())
+        });
+        let cpus = pci::local_cpulist(&address).or_else(|| {
+            topology
+                .affinity(&label, "CPU Affinity")
+                .map(str::to_string)
+        });
+        let nic = topology.closest_nic(&label);
+
+        println!(
+            "GPU {} ({}): NUMA node {}, CPUs {}, closest NIC {}",
+            gpu.index,
+            address,
+            numa_node.map_or("none".to_string(), |node| node.to_string()),
+            cpus.as_deref().unwrap_or("unknown"),
+            nic.map_or("none".to_string(), |(name, link)| format!(
+                "{name} ({link})"
+            ))
+        );
+
+        bindings.push(suggested_binding(
+            gpu.index,
+            numa_node,
+            nic.map(|(name, _)| name),
+        ));
+    }
+
+    println!();
+    println!("Suggested bindings, one process per GPU:");
+    for binding in bindings {
+        println!("  {binding}");
+    }
+    Ok(())
+}
+
+/// `gpu_index` is nvidia-smi's index, which CUDA only uses as well with
+/// CUDA_DEVICE_ORDER=PCI_BUS_ID, by default it puts the fastest GPU first.
+fn suggested_binding(gpu_index: u32, numa_node: Option<u32>, nic: Option<&str>) -> String {
+    let numactl = match numa_node {
+        Some(node) => format!("numactl --cpunodebind={node} --membind={node} "),
+        None => String::new(),
+    };
+    let nccl = nic
+        .map(|name| format!("  # NCCL_IB_HCA={name}"))
+        .unwrap_or_default();
+    format!(
+        "CUDA_DEVICE_ORDER=PCI_BUS_ID CUDA_VISIBLE_DEVICES={gpu_index} {numactl}<command>{nccl}"
+    )
+}
+
+// The matrix is tab separated, rows and columns are labelled with the
+// devices, followed by the legends:
+//
+//         GPU0    GPU1    NIC0    CPU Affinity    NUMA Affinity   GPU NUMA ID
+// GPU0     X      NV18    PXB     0-51,104-155    0               N/A
+// GPU1    NV18     X      SYS     52-103,156-207  1               N/A
+// NIC0    PXB     SYS      X
+//
+// Legend:
+//   ...
+// NIC Legend:
+//
+//   NIC0: mlx5_0
+fn parse_topology(output: &str) -> io::Result<TopologyMatrix> {
+    let output = strip_ansi_escapes(output);
+    let mut lines = output.lines().skip_while(|line| line.trim().is_empty());
+    let header = lines
+        .next()
+        .ok_or_else(|| io::Error::other("nvidia-smi topo -m printed nothing"))?;
+    let columns: Vec<String> = header
+        .split('\t')
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .map(str::to_string)
+        .collect();
+    let devices: Vec<String> = columns
+        .iter()
+        .filter(|column| {
+            (column.starts_with("GPU") && !column.contains(' ')) || column.starts_with("NIC")
+        })
+        .cloned()
+        .collect();
+
+    let mut links = HashMap::new();
+    let mut affinities = HashMap::new();
+    for line in lines.by_ref().take_while(|line| !line.trim().is_empty()) {
+        // Some columns are separated by two tabs, there are no empty cells.
+        let mut cells = line
+            .split('\t')
+            .map(str::trim)
+            .filter(|cell| !cell.is_empty());
+        let Some(device) = cells.next() else {
+            continue;
+        };
+        for (column, cell) in columns.iter().zip(cells) {
+            let key = (device.to_string(), column.clone());
+            if devices.contains(column) {
+                links.insert(key, cell.to_string());
+            } else {
+                affinities.insert(key, cell.to_string());
+            }
+        }
+    }
+    if devices.is_empty() || links.is_empty() {
+        return Err(io::Error::other(format!(
+            "Unexpected nvidia-smi topo -m output: {header}"
+        )));
+    }
+
+    let nic_names = lines
+        .filter_map(|line| {
+            let (label, name) = line.trim().split_once(':')?;
+            label
+                .starts_with("NIC")
+                .then(|| (label.to_string(), name.trim().to_string()))
+        })
+        .collect();
+
+    Ok(TopologyMatrix {
+        devices,
+        links,
+        affinities,
+        nic_names,
+    })
+}
+
+// Some nvidia-smi versions underline the header with "\x1b[4m...\x1b[0m".
+fn strip_ansi_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HGX_H100: &str = include_str!("../tests/fixtures/nvidia-smi-topo-8x-h100.txt");
+    const PCIE_BOX: &str = include_str!("../tests/fixtures/nvidia-smi-topo-2x-pcie.txt");
+
+    #[test]
+    fn ranks_links_from_closest_to_farthest() {
+        let links = ["SYS", "PXB", "NV4", "X", "NODE", "NV18", "PIX", "PHB"];
+        let mut sorted = links;
+        sorted.sort_by_key(|link| link_rank(link));
+        assert_eq!(
+            sorted,
+            ["NV18", "NV4", "PIX", "PXB", "PHB", "NODE", "SYS", "X"]
+        );
+        assert!(link_rank("SYS") < link_rank("unknown"));
+    }
+
+    #[test]
+    fn strips_ansi_escapes() {
+        assert_eq!(strip_ansi_escapes("\x1b[4mGPU0\tGPU1\x1b[0m"), "GPU0\tGPU1");
+    }
+
+    #[test]
+    fn parses_an_nvlink_matrix() {
+        let topology = parse_topology(HGX_H100).unwrap();
+        assert_eq!(topology.labels("GPU").count(), 8);
+        assert_eq!(topology.labels("NIC").count(), 8);
+        assert_eq!(topology.link("GPU0", "GPU7"), Some("NV18"));
+        assert_eq!(topology.link("GPU0", "GPU0"), Some("X"));
+        assert_eq!(topology.link("NIC3", "NIC4"), Some("SYS"));
+        assert_eq!(topology.affinity("GPU5", "NUMA Affinity"), Some("1"));
+        assert_eq!(
+            topology.affinity("GPU2", "CPU Affinity"),
+            Some("0-47,96-143")
+        );
+        assert_eq!(topology.affinity("GPU2", "GPU NUMA ID"), None);
+        assert_eq!(topology.nic_names["NIC6"], "mlx5_6");
+    }
+
+    #[test]
+    fn finds_the_nic_behind_each_gpus_switch() {
+        let topology = parse_topology(HGX_H100).unwrap();
+        for gpu in 0..8 {
+            let expected = format!("mlx5_{gpu}");
+            assert_eq!(
+                topology.closest_nic(&format!("GPU{gpu}")),
+                Some((expected.as_str(), "PXB"))
+            );
+        }
+    }
+
+    #[test]
+    fn parses_a_pcie_box() {
+        let topology = parse_topology(PCIE_BOX).unwrap();
+        assert_eq!(topology.link("GPU0", "GPU1"), Some("PHB"));
+        assert_eq!(topology.affinity("GPU0", "NUMA Affinity"), None);
+        assert_eq!(topology.closest_nic("GPU0"), Some(("mlx5_1", "PXB")));
+        assert_eq!(topology.closest_nic("GPU1"), Some(("mlx5_0", "PIX")));
+        assert_eq!(topology.closest_nic("GPU2"), None);
+    }
+
+    #[test]
+    fn rejects_unexpected_output() {
+        assert!(parse_topology("").is_err());
+        assert!(parse_topology(
+            "NVIDIA-SMI has failed because it couldn't communicate with the NVIDIA driver.\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn binds_by_pci_bus_order() {
+        assert_eq!(
+            suggested_binding(3, Some(0), Some("mlx5_3")),
+            "CUDA_DEVICE_ORDER=PCI_BUS_ID CUDA_VISIBLE_DEVICES=3 numactl --cpunodebind=0 --membind=0 <command>  # NCCL_IB_HCA=mlx5_3"
+        );
+        assert_eq!(
+            suggested_binding(1, None, None),
+            "CUDA_DEVICE_ORDER=PCI_BUS_ID CUDA_VISIBLE_DEVICES=1 <command>"
+        );
+    }
+}
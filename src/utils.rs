@@ -1,16 +1,24 @@
 use std::{
+    env,
     ffi::{OsStr, OsString},
     io::{self, Write},
     path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
 };
 
+const RESUME_UNIT_NAME: &str = "ignite-resume.service";
+const RESUME_UNIT_PATH: &str = "/etc/systemd/system/ignite-resume.service";
+/// Set by the resume unit, so that only the resumed run removes it.
+const RESUME_ENV: &str = "IGNITE_RESUMED";
+
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct CommandOptions<'a> {
     pub(crate) check: bool,
     pub(crate) input: Option<&'a str>,
     pub(crate) silent: bool,
     pub(crate) retries: usize,
+    /// Extra environment variables for the command
+    pub(crate) env: &'a [(&'a str, &'a str)],
 }
 
 impl Default for CommandOptions<'_> {
@@ -20,6 +28,7 @@ impl Default for CommandOptions<'_> {
             input: None,
             silent: false,
             retries: 0,
+            env: &[],
         }
     }
 }
@@ -58,6 +67,7 @@ where
     loop {
         let mut cmd = Command::new(program);
         cmd.args(&args);
+        cmd.envs(options.env.iter().copied());
 
         if options.input.is_some() {
             cmd.stdin(Stdio::piped());
@@ -101,14 +111,49 @@ where
     }
 }
 
-pub(crate) fn download_file(url: &str, md5sum: &str) -> io::Result<PathBuf> {
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Checksum<'a> {
+    Md5(&'a str),
+    Sha256(&'a str),
+}
+
+impl Checksum<'_> {
+    fn program(&self) -> &'static str {
+        match self {
+            Checksum::Md5(_) => "md5sum",
+            Checksum::Sha256(_) => "sha256sum",
+        }
+    }
+
+    fn expected(&self) -> &str {
+        match self {
+            Checksum::Md5(sum) | Checksum::Sha256(sum) => sum,
+        }
+    }
+}
+
+pub(crate) fn download_file(url: &str, checksum: Checksum<'_>) -> io::Result<PathBuf> {
     let filename = url.split('/').next_back().unwrap_or("downloaded_file");
+    download_file_as(url, filename, checksum)
+}
+
+/// Downloads `url` to `/tmp/<filename>` and verifies it. A file left there by
+/// an earlier run is reused only if it matches the checksum.
+pub(crate) fn download_file_as(
+    url: &str,
+    filename: &str,
+    checksum: Checksum<'_>,
+) -> io::Result<PathBuf> {
     let dest_path = format!("/tmp/{}", filename);
 
     let file_path = Path::new(&dest_path);
     if file_path.exists() {
-        println!("File {dest_path} already exists, skipping download.");
-        return Ok(dest_path.into());
+        if file_matches_checksum(&dest_path, checksum)? {
+            println!("File {dest_path} already exists, skipping download.");
+            return Ok(dest_path.into());
+        }
+        println!("File {dest_path} does not match the checksum, downloading it again.");
+        std::fs::remove_file(file_path)?;
     }
 
     println!("Downloading {url} to {dest_path} ...");
@@ -118,24 +163,57 @@ pub(crate) fn download_file(url: &str, md5sum: &str) -> io::Result<PathBuf> {
         CommandOptions::default(),
     )?;
 
+    if !file_matches_checksum(&dest_path, checksum)? {
+        return Err(io::Error::other(format!(
+            "The downloaded file checksum does not match. Won't continue installation. \
+                Try deleting {dest_path} and trying again.",
+        )));
+    }
+
+    Ok(dest_path.into())
+}
+
+fn file_matches_checksum(path: &str, checksum: Checksum<'_>) -> io::Result<bool> {
     let output = run_cmd(
-        "md5sum",
-        [&dest_path],
+        checksum.program(),
+        [path],
         CommandOptions {
             silent: true,
             ..Default::default()
         },
     )?;
-    let checksum = output.stdout.split_whitespace().next().unwrap_or("");
+    let actual = output.stdout.split_whitespace().next().unwrap_or("");
+    Ok(actual.eq_ignore_ascii_case(checksum.expected()))
+}
 
-    if checksum != md5sum {
-        return Err(io::Error::other(format!(
-            "The installer file checksum does not match. Won't continue installation. \
-                Try deleting {dest_path} and trying again.",
-        )));
-    }
+pub(crate) fn fetch_json(url: &str) -> io::Result<serde_json::Value> {
+    let output = run_cmd(
+        "curl",
+        ["-fsSL", url],
+        CommandOptions {
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    serde_json::from_str(&output.stdout)
+        .map_err(|err| io::Error::other(format!("Invalid JSON from {url}: {err}")))
+}
 
-    Ok(dest_path.into())
+/// Unpacks an archive whose contents sit in a single top-level directory, as
+/// NVIDIA's redistributable tarballs do, straight into `dest_dir`.
+pub(crate) fn extract_archive(archive: &Path, dest_dir: &Path) -> io::Result<()> {
+    run_cmd(
+        "tar",
+        [
+            OsStr::new("-xf"),
+            archive.as_os_str(),
+            OsStr::new("-C"),
+            dest_dir.as_os_str(),
+            OsStr::new("--strip-components=1"),
+        ],
+        CommandOptions::default(),
+    )?;
+    Ok(())
 }
 
 pub(crate) fn get_kernel_version() -> io::Result<String> {
@@ -208,20 +286,123 @@ pub(crate) fn unlock_kernel_updates_debian() -> io::Result<()> {
 
 pub(crate) fn reboot() -> ! {
     println!("The system needs to be rebooted to complete the installation process.");
-    println!("The process will be continued after the reboot.");
+    match schedule_resume_after_reboot() {
+        Ok(()) => println!("The process will be continued after the reboot."),
+        Err(err) => println!(
+            "Could not schedule the process to continue after the reboot ({err}), \
+            run the same command again once the system is back up."
+        ),
+    }
 
     run_cmd("reboot", ["now"], CommandOptions::default()).unwrap();
     std::process::exit(0);
 }
 
+/// Installs a one-shot unit that runs the current command line again on the
+/// next boot. The resumed run removes it through `clear_pending_resume`.
+fn schedule_resume_after_reboot() -> io::Result<()> {
+    let exe = env::current_exe()?;
+    let command_line = std::iter::once(exe.to_string_lossy().into_owned())
+        .chain(env::args().skip(1))
+        .map(|arg| systemd_quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let unit = format!(
+        "[Unit]\n\
+        Description=Continue ignite installation after reboot\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        Environment={RESUME_ENV}=1\n\
+        ExecStart={command_line}\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n"
+    );
+    std::fs::write(RESUME_UNIT_PATH, unit)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    run_cmd(
+        "systemctl",
+        ["enable", RESUME_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+    Ok(())
+}
+
+/// Whether this process was started by the unit `reboot` scheduled.
+pub(crate) fn is_resumed_run() -> bool {
+    env::var_os(RESUME_ENV).is_some_and(|value| value == "1")
+}
+
+pub(crate) fn clear_pending_resume() -> io::Result<()> {
+    if !Path::new(RESUME_UNIT_PATH).exists() {
+        return Ok(());
+    }
+
+    run_cmd(
+        "systemctl",
+        ["disable", RESUME_UNIT_NAME],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    std::fs::remove_file(RESUME_UNIT_PATH)?;
+    Ok(())
+}
+
+pub(crate) fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+pub(crate) fn command_exists(program: &str) -> io::Result<bool> {
+    let output = run_cmd(
+        "which",
+        [program],
+        CommandOptions {
+            check: false,
+            silent: true,
+            ..Default::default()
+        },
+    )?;
+    Ok(output.status.success())
+}
+
 pub(crate) fn get_distro_id() -> io::Result<String> {
+    read_os_release_field("ID")
+}
+
+pub(crate) fn get_distro_version_id() -> io::Result<String> {
+    read_os_release_field("VERSION_ID")
+}
+
+fn read_os_release_field(field: &str) -> io::Result<String> {
     let content = std::fs::read_to_string("/etc/os-release")?;
+    let prefix = format!("{field}=");
     for line in content.lines() {
-        if let Some(id) = line.strip_prefix("ID=") {
-            return Ok(id.trim_matches('"').to_string());
+        if let Some(value) = line.strip_prefix(&prefix) {
+            return Ok(value.trim_matches('"').to_string());
         }
     }
-    Err(io::Error::other(
-        "Could not determine distro from /etc/os-release",
-    ))
+    Err(io::Error::other(format!(
+        "Could not determine {field} from /etc/os-release"
+    )))
+}
+
+/// Compares dotted version strings such as driver (`570.86.10`) or toolkit
+/// (`12.8`) versions numerically.
+pub(crate) fn version_key(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
 }
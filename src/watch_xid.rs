@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use clap::{Args, Subcommand, ValueEnum};
+
+use crate::{
+    utils::{command_exists, run_cmd, systemd_quote, CommandOptions},
+    xid::{self, XidEvent, XidSeverity},
+    CloudProvider,
+};
+
+const WATCH_XID_UNIT_NAME: &str = "ignite-watch-xid.service";
+const WATCH_XID_UNIT_PATH: &str = "/etc/systemd/system/ignite-watch-xid.service";
+const KMSG_PATH: &str = "/dev/kmsg";
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub(crate) struct WatchXidCommand {
+    #[command(subcommand)]
+    pub(crate) unit: Option<WatchXidUnitCommand>,
+
+    #[command(flatten)]
+    pub(crate) options: WatchXidOptions,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum WatchXidUnitCommand {
+    /// Install and start a systemd unit that runs the watcher with these options
+    InstallUnit(WatchXidOptions),
+
+    /// Stop and remove the watcher's systemd unit
+    UninstallUnit,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct WatchXidOptions {
+    /// Least severe Xid that triggers the actions, less severe ones are only logged
+    #[arg(long, value_enum, default_value = "warning")]
+    pub(crate) min_severity: XidSeverity,
+
+    /// Append each alert to this file, e.g. for a node health check to pick up
+    #[arg(long)]
+    pub(crate) marker_file: Option<String>,
+
+    /// POST each alert as JSON to this URL
+    #[arg(long)]
+    pub(crate) webhook: Option<String>,
+
+    /// Run this shell command on each alert, e.g. to cordon the node. The
+    /// alert is passed in XID_CODE, XID_SEVERITY, XID_PCI_ADDRESS and XID_MESSAGE
+    #[arg(long)]
+    pub(crate) command: Option<String>,
+
+    /// Seconds during which repeats of the same Xid on the same GPU don't
+    /// trigger the actions again
+    #[arg(long, default_value_t = 600)]
+    pub(crate) cooldown: u64,
+}
+
+impl WatchXidOptions {
+    /// The command line flags that reproduce these options, for the unit.
+    fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--min-severity".to_string(),
+            self.min_severity.to_string(),
+            "--cooldown".to_string(),
+            self.cooldown.to_string(),
+        ];
+        for (flag, value) in [
+            ("--marker-file", &self.marker_file),
+            ("--webhook", &self.webhook),
+            ("--command", &self.command),
+        ] {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+            }
+        }
+        args
+    }
+}
+
+pub(crate) fn run_watch_xid_command(
+    cloud_provider: CloudProvider,
+    command: WatchXidCommand,
+) -> io::Result<()> {
+    match command.unit {
+        Some(WatchXidUnitCommand::InstallUnit(options)) => install_unit(cloud_provider, &options),
+        Some(WatchXidUnitCommand::UninstallUnit) => uninstall_unit(),
+        None => watch(&command.options),
+    }
+}
+
+/// Decides which kernel log lines trigger the alert actions.
+struct XidAlerter {
+    min_severity: XidSeverity,
+    cooldown: Duration,
+    /// When each GPU last alerted for each code
+    last_alerts: HashMap<(String, u32), Instant>,
+}
+
+impl XidAlerter {
+    fn new(options: &WatchXidOptions) -> Self {
+        Self {
+            min_severity: options.min_severity,
+            cooldown: Duration::from_secs(options.cooldown),
+            last_alerts: HashMap::new(),
+        }
+    }
+
+    /// Calls `alert` for an Xid in `line` that is severe enough and not a
+    /// repeat within the cooldown, as of `now`.
+    fn handle_line(&mut self, line: &str, now: Instant, alert: &mut impl FnMut(&XidEvent)) {
+        let Some(event) = xid::parse_xid_line(line) else {
+            return;
+        };
+        let severity = event.severity();
+        println!(
+            "{severity} Xid {} on {}: {} ({})",
+            event.code,
+            event.pci_address,
+            event.description(),
+            event.message
+        );
+        if severity < self.min_severity {
+            return;
+        }
+
+        let key = (event.pci_address.clone(), event.code);
+        if self
+            .last_alerts
+            .get(&key)
+            .is_some_and(|last| now.duration_since(*last) < self.cooldown)
+        {
+            return;
+        }
+        self.last_alerts.insert(key, now);
+        alert(&event);
+    }
+}
+
+/// Follows the kernel log until it ends, which for journalctl and
+/// `/dev/kmsg` is never.
+fn watch(options: &WatchXidOptions) -> io::Result<()> {
+    let mut alerter = XidAlerter::new(options);
+    let mut run_alert_actions = |event: &XidEvent| {
+        // A failing action is reported but must not stop the watcher.
+        if let Err(err) = run_actions(options, event) {
+            eprintln!("Alert actions for Xid {} failed: {err}", event.code);
+        }
+    };
+    let mut handle_line =
+        |line: &str| alerter.handle_line(line, Instant::now(), &mut run_alert_actions);
+
+    if command_exists("journalctl")? {
+        println!("Watching the kernel log through journalctl for Xid errors...");
+        let mut child = Command::new("journalctl")
+            .args(["-k", "-f", "-n", "0", "-o", "cat", "-q"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("journalctl has no stdout"))?;
+        for line in BufReader::new(stdout).lines() {
+            handle_line(&line?);
+        }
+        child.wait()?;
+        return Err(io::Error::other("journalctl exited, no longer watching"));
+    }
+
+    println!("Watching {KMSG_PATH} for Xid errors...");
+    let mut kmsg = File::open(KMSG_PATH)?;
+    // Only new messages, the ring buffer's history is what `cuda doctor` is for.
+    kmsg.seek(SeekFrom::End(0))?;
+    let mut kmsg = BufReader::new(kmsg);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match kmsg.read_line(&mut line) {
+            Ok(0) => return Err(io::Error::other(format!("{KMSG_PATH} was closed"))),
+            Ok(_) => handle_line(&line),
+            // Messages were overwritten before we read them, carry on.
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn run_actions(options: &WatchXidOptions, event: &XidEvent) -> io::Result<()> {
+    let severity = event.severity().to_string();
+    let code = event.code.to_string();
+    let hostname = fs::read_to_string(HOSTNAME_PATH).unwrap_or_default();
+    let hostname = hostname.trim();
+
+    if let Some(marker_file) = &options.marker_file {
+        if let Some(parent) = Path::new(marker_file).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut marker = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(marker_file)?;
+        writeln!(
+            marker,
+            "{severity} Xid {code} on {}: {}",
+            event.pci_address,
+            event.description()
+        )?;
+    }
+
+    if let Some(webhook) = &options.webhook {
+        let payload = serde_json::json!({
+            "host": hostname,
+            "pci_address": event.pci_address,
+            "code": event.code,
+            "severity": severity,
+            "description": event.description(),
+            "message": event.message,
+        })
+        .to_string();
+        run_cmd(
+            "curl",
+            [
+                "-fsS",
+                "-X",
+                "POST",
+                "-H",
+                "Content-Type: application/json",
+                "--data",
+                payload.as_str(),
+                webhook.as_str(),
+            ],
+            CommandOptions {
+                retries: 2,
+                ..Default::default()
+            },
+        )?;
+    }
+
+    if let Some(command) = &options.command {
+        run_cmd(
+            "sh",
+            ["-c", command.as_str()],
+            CommandOptions {
+                env: &[
+                    ("XID_CODE", code.as_str()),
+                    ("XID_SEVERITY", severity.as_str()),
+                    ("XID_PCI_ADDRESS", event.pci_address.as_str()),
+                    ("XID_MESSAGE", event.message.as_str()),
+                ],
+                ..Default::default()
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+fn install_unit(cloud_provider: CloudProvider, options: &WatchXidOptions) -> io::Result<()> {
+    let exe = env::current_exe()?.to_string_lossy().into_owned();
+    let cloud_provider = cloud_provider
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    let command_line = [exe, "--cloud-provider".to_string(), cloud_provider]
+        .into_iter()
+        .chain(["cuda".to_string(), "watch-xid".to_string()])
+        .chain(options.to_args())
+        .map(|arg| systemd_quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let unit = format!(
+        "[Unit]\n\
+        Description=Watch the kernel log for NVIDIA Xid errors\n\
+        After=systemd-journald.service network-online.target\n\
+        Wants=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=simple\n\
+        ExecStart={command_line}\n\
+        Restart=always\n\
+        RestartSec=5\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n"
+    );
+    fs::write(WATCH_XID_UNIT_PATH, unit)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    run_cmd(
+        "systemctl",
+        ["enable", "--now", WATCH_XID_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+
+    println!(
+        "Installed {WATCH_XID_UNIT_PATH}, follow it with `journalctl -u {WATCH_XID_UNIT_NAME} -f`."
+    );
+    Ok(())
+}
+
+fn uninstall_unit() -> io::Result<()> {
+    if !Path::new(WATCH_XID_UNIT_PATH).exists() {
+        println!("The Xid watcher unit is not installed.");
+        return Ok(());
+    }
+
+    run_cmd(
+        "systemctl",
+        ["disable", "--now", WATCH_XID_UNIT_NAME],
+        CommandOptions::default(),
+    )?;
+    fs::remove_file(WATCH_XID_UNIT_PATH)?;
+    run_cmd("systemctl", ["daemon-reload"], CommandOptions::default())?;
+    println!("Removed {WATCH_XID_UNIT_PATH}.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FALLEN_OFF_THE_BUS: &str = "[ 5012.334109] NVRM: Xid (PCI:0000:05:00): 79, pid='<unknown>', name=<unknown>, GPU has fallen off the bus.";
+    const ILLEGAL_ADDRESS: &str =
+        "NVRM: Xid (PCI:0000:04:00): 13, pid=1234, name=python, Graphics Exception: ESR 0x404600=0x80000002";
+    const ROW_REMAP: &str =
+        "kernel: NVRM: Xid (PCI:0000:04:00): 63, pid='<unknown>', name=<unknown>, Row Remapper: New row marked for remapping";
+
+    fn alerter(min_severity: XidSeverity, cooldown: u64) -> XidAlerter {
+        XidAlerter::new(&WatchXidOptions {
+            min_severity,
+            marker_file: None,
+            webhook: None,
+            command: None,
+            cooldown,
+        })
+    }
+
+    /// Feeds `lines` at the given seconds after the start, returns the alerts.
+    fn alerts(alerter: &mut XidAlerter, lines: &[(u64, &str)]) -> Vec<(String, u32)> {
+        let start = Instant::now();
+        let mut alerts = Vec::new();
+        for (seconds, line) in lines {
+            alerter.handle_line(line, start + Duration::from_secs(*seconds), &mut |event| {
+                alerts.push((event.pci_address.clone(), event.code))
+            });
+        }
+        alerts
+    }
+
+    #[test]
+    fn maps_codes_to_severities() {
+        let severity = |line| xid::parse_xid_line(line).unwrap().severity();
+        assert_eq!(severity(FALLEN_OFF_THE_BUS), XidSeverity::Critical);
+        assert_eq!(severity(ROW_REMAP), XidSeverity::Warning);
+        assert_eq!(severity(ILLEGAL_ADDRESS), XidSeverity::Application);
+    }
+
+    #[test]
+    fn alerts_at_or_above_the_minimum_severity() {
+        let lines = [
+            (0, ILLEGAL_ADDRESS),
+            (0, ROW_REMAP),
+            (0, FALLEN_OFF_THE_BUS),
+        ];
+        assert_eq!(
+            alerts(&mut alerter(XidSeverity::Warning, 600), &lines),
+            [
+                ("0000:04:00".to_string(), 63),
+                ("0000:05:00".to_string(), 79)
+            ]
+        );
+        assert_eq!(
+            alerts(&mut alerter(XidSeverity::Critical, 600), &lines),
+            [("0000:05:00".to_string(), 79)]
+        );
+        assert_eq!(
+            alerts(&mut alerter(XidSeverity::Application, 600), &lines).len(),
+            3
+        );
+    }
+
+    #[test]
+    fn ignores_other_kernel_messages() {
+        let lines = [
+            (
+                0,
+                "NVRM: GPU at PCI:0000:05:00: GPU-1a2b3c4d-5e6f-7081-92a3-b4c5d6e7f809",
+            ),
+            (
+                0,
+                "nvidia-modeset: Loading NVIDIA Kernel Mode Setting Driver",
+            ),
+        ];
+        assert!(alerts(&mut alerter(XidSeverity::Application, 600), &lines).is_empty());
+    }
+
+    #[test]
+    fn repeats_alert_again_after_the_cooldown() {
+        let other_gpu = FALLEN_OFF_THE_BUS.replace("0000:05:00", "0000:06:00");
+        let lines = [
+            (0, FALLEN_OFF_THE_BUS),
+            (10, FALLEN_OFF_THE_BUS),
+            (20, other_gpu.as_str()),
+            (599, FALLEN_OFF_THE_BUS),
+            (600, FALLEN_OFF_THE_BUS),
+        ];
+        assert_eq!(
+            alerts(&mut alerter(XidSeverity::Warning, 600), &lines),
+            [
+                ("0000:05:00".to_string(), 79),
+                ("0000:06:00".to_string(), 79),
+                ("0000:05:00".to_string(), 79),
+            ]
+        );
+        // Without a cooldown every line alerts.
+        assert_eq!(
+            alerts(&mut alerter(XidSeverity::Warning, 0), &lines).len(),
+            5
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use clap::ValueEnum;
+
+/// How bad an Xid is, following NVIDIA's Xid catalog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub(crate) enum XidSeverity {
+    /// Caused by the application, e.g. an illegal memory access
+    Application,
+    /// Recoverable, but the GPU should be reset or checked soon
+    Warning,
+    /// The GPU is unusable until it is reset or replaced
+    Critical,
+}
+
+impl std::fmt::Display for XidSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            XidSeverity::Application => write!(f, "application"),
+            XidSeverity::Warning => write!(f, "warning"),
+            XidSeverity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct XidEvent {
+    /// For example `0000:04:00`
+    pub(crate) pci_address: String,
+    pub(crate) code: u32,
+    /// The rest of the kernel message after the code
+    pub(crate) message: String,
+}
+
+impl XidEvent {
+    pub(crate) fn severity(&self) -> XidSeverity {
+        match self.code {
+            48 | 62 | 64 | 74 | 79 | 92 | 95 | 119 | 120 | 140 => XidSeverity::Critical,
+            13 | 31 | 43 | 45 | 68 | 69 => XidSeverity::Application,
+            _ => XidSeverity::Warning,
+        }
+    }
+
+    pub(crate) fn description(&self) -> &'static str {
+        match self.code {
+            13 => "graphics engine exception",
+            31 => "GPU memory page fault",
+            43 => "GPU stopped processing",
+            45 => "preemptive cleanup",
+            48 => "double bit ECC error",
+            61 => "internal micro-controller breakpoint",
+            62 => "internal micro-controller halt",
+            63 => "ECC page retirement or row remapping recorded",
+            64 => "ECC page retirement or row remapping failed",
+            74 => "NVLink error",
+            79 => "GPU has fallen off the bus",
+            92 => "high single bit ECC error rate",
+            94 => "contained ECC error",
+            95 => "uncontained ECC error",
+            119 => "GSP RPC timeout",
+            120 => "GSP error",
+            140 => "unrecovered ECC error",
+            _ => "see NVIDIA's Xid catalog",
+        }
+    }
+}
+
+// "NVRM: Xid (PCI:0000:04:00): 79, pid='<unknown>', name=<unknown>, GPU has fallen off the bus."
+// possibly behind a dmesg timestamp or a journal prefix.
+pub(crate) fn parse_xid_line(line: &str) -> Option<XidEvent> {
+    let (_, rest) = line.split_once("NVRM: Xid (")?;
+    let (device, rest) = rest.split_once("):")?;
+    let pci_address = device.strip_prefix("PCI:").unwrap_or(device).to_string();
+    let rest = rest.trim_start();
+    let code_len = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let code = rest[..code_len].parse().ok()?;
+    let message = rest[code_len..].trim_start_matches(',').trim().to_string();
+
+    Some(XidEvent {
+        pci_address,
+        code,
+        message,
+    })
+}